@@ -0,0 +1,5371 @@
+//! Share files and folders over the local network from a single command.
+//!
+//! The `wfpush` binary is a thin command-line wrapper around [`run`]; other
+//! programs can build a [`ServerConfig`] and serve it the same way.
+
+// ==========================
+// Imports
+// ==========================
+use async_zip::{
+    Compression, ZipDateTime, ZipDateTimeBuilder, ZipEntryBuilder, tokio::write::ZipFileWriter,
+};
+use axum::{
+    Json, Router,
+    body::{Body, Bytes},
+    extract::{
+        ConnectInfo, DefaultBodyLimit, Multipart, OriginalUri, Path as AxumPath, Query, Request,
+        State, connect_info::Connected, multipart::Field,
+    },
+    http::{
+        Extensions, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Version,
+        header::{self, RANGE},
+    },
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{get, head, post},
+    serve::IncomingStream,
+};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures_util::{Stream, StreamExt, TryStreamExt, future, stream};
+use image::Luma;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use ipnet::IpNet;
+use lru::LruCache;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use qrcode::{QrCode, render::unicode};
+use rustls::{
+    RootCertStore,
+    server::{WebPkiClientVerifier, danger::ClientCertVerifier},
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    fs::Metadata,
+    io::{self, IsTerminal},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    task::ready,
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    fs::{self, File},
+    io::DuplexStream,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    net::{TcpListener, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore, TryAcquireError},
+    time::Sleep,
+};
+use tokio_util::{compat::FuturesAsyncWriteCompatExt, io::ReaderStream, sync::CancellationToken};
+use tower_http::{
+    compression::{CompressionLayer, Predicate, predicate::DefaultPredicate},
+    cors::{AllowOrigin, CorsLayer},
+};
+use tracing::{debug, error, info, warn};
+use unicase::UniCase;
+use uuid::Uuid;
+use walkdir::WalkDir;
+
+// ==========================
+// Configuration
+// ==========================
+/// Everything [`run`] needs to know about a share. `Default` matches the
+/// command-line defaults: the current directory on port 3000.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Files or folders to serve; several paths are listed side by side at the root
+    pub paths: Vec<PathBuf>,
+    /// Serve the files listed in this TOML manifest, each at its own route and
+    /// optionally with its own credentials, instead of `paths`
+    pub manifest: Option<PathBuf>,
+    /// Also serve pipes, devices and other non-regular files, streamed without a length
+    pub allow_special: bool,
+    /// Follow symlinks inside shared folders, as long as they lead somewhere
+    /// inside the share; without it they are hidden and answer 403
+    pub serve_symlinks: bool,
+    /// Path the single shared file is downloaded from, `/download` by default
+    pub route: String,
+    /// Download filename to offer for a single shared file instead of its on-disk name
+    pub download_name: Option<String>,
+    /// `Content-Type` to send for every file in place of the type guessed from its name
+    pub content_type: Option<String>,
+    /// Port to listen on
+    pub port: u16,
+    /// Use the first free port in this inclusive range instead of `port`
+    pub port_range: Option<(u16, u16)>,
+    /// Interface address to listen on (all interfaces by default)
+    pub bind: Option<IpAddr>,
+    /// Listen on all IPv6 interfaces and advertise the IPv6 address
+    pub ipv6: bool,
+    /// Listen on all IPv4 and all IPv6 interfaces at once, on the same port,
+    /// and advertise an address of each family
+    pub dual_stack: bool,
+    /// Advertise the address of this network interface, e.g. wlan0
+    pub interface: Option<String>,
+    /// Listen on this Unix domain socket instead of TCP
+    #[cfg(unix)]
+    pub unix: Option<PathBuf>,
+    /// Parallel downloads suggested to clients of a selection
+    pub parallel_downloads: u16,
+    /// Let browsers display previewable files instead of downloading them
+    pub inline: bool,
+    /// Download command suggested by the banner and landing page; by default the
+    /// banner follows this machine's platform and the landing page each visitor's
+    pub hint: Option<DownloadHint>,
+    /// Print the server URL as a QR code in the terminal
+    pub qr: bool,
+    /// Also save the server URL as a QR code PNG image at this path
+    pub qr_png: Option<PathBuf>,
+    /// Open the server's front page in the default browser once it is listening
+    pub open: bool,
+    /// Name advertised over mDNS, reachable as `<hostname>.local`
+    pub hostname: String,
+    /// Require HTTP Basic authentication with these credentials
+    pub auth: Option<Credentials>,
+    /// Require HTTP Basic authentication against the `user:password` lines of
+    /// this file, as written by `htpasswd -B`; passwords may be bcrypt hashes
+    pub password_file: Option<PathBuf>,
+    /// Serve everything under `/<token>/` so only people with the link get in
+    pub token: Option<String>,
+    /// Cap each download at this many bytes per second
+    pub limit_rate: Option<u64>,
+    /// Under `limit_rate`, let this many bytes of each download through at full
+    /// speed before pacing starts, so small files and previews stay instant
+    pub throttle_after: u64,
+    /// Read and send files in chunks of this many bytes
+    pub chunk_size: usize,
+    /// Ignore `Range` headers and always send whole files, advertising
+    /// `Accept-Ranges: none` so clients don't try to resume or split downloads
+    pub no_ranges: bool,
+    /// Offer the shared folder as a single archive at `/download.zip`
+    pub zip: bool,
+    /// Send a SHA-256 Digest header with full downloads
+    pub checksum: bool,
+    /// Files larger than this are not hashed automatically; `/checksum` still hashes them on request
+    pub hash_threshold: u64,
+    /// Send `nosniff`, `X-Frame-Options: DENY` and a Content-Security-Policy,
+    /// which also sandboxes served HTML and SVG files
+    pub security_headers: bool,
+    /// Send CORS headers so pages on other origins can fetch the share with GET/HEAD
+    pub cors: bool,
+    /// Origins allowed under `cors`, e.g. `https://example.com`; empty allows any origin
+    pub cors_origins: Vec<String>,
+    /// `Cache-Control` sent with file downloads, e.g. `max-age=3600`
+    pub cache_control: String,
+    /// Only expose the top-level files of a shared folder: subfolders are left
+    /// out of listings and archives, and paths reaching into them get 400
+    pub basename_only: bool,
+    /// Write a `sha256sum -c` compatible `<name>.sha256` next to each shared file at startup
+    pub checksum_file: bool,
+    /// Watch the shared paths and drop cached checksums when files change
+    pub watch: bool,
+    /// Accept uploads into this folder
+    pub upload: Option<PathBuf>,
+    /// Largest accepted upload request in bytes (unlimited by default)
+    pub max_upload_size: Option<u64>,
+    /// Also accept tus resumable uploads at `/uploads` into the `upload` folder
+    pub resumable: bool,
+    /// What an upload does when a file of the same name is already in the folder
+    pub upload_conflict: UploadConflict,
+    /// Stop serving after this long
+    pub timeout: Option<Duration>,
+    /// Stop serving after this many completed downloads
+    pub max_downloads: Option<u64>,
+    /// Delete the shared files once `max_downloads` or `timeout` stops the server
+    pub delete_when_done: bool,
+    /// POST a JSON summary of each completed download to this http(s) URL
+    pub webhook: Option<String>,
+    /// Serve at most this many downloads at once; extra requests get 503
+    pub max_connections: Option<u32>,
+    /// Allow each client IP this many requests per minute; extra requests get 429
+    pub rate_limit: Option<u32>,
+    /// Only serve clients inside these networks, answering others with 403;
+    /// empty serves everyone
+    pub allow: Vec<IpNet>,
+    /// Reshare this http(s) URL as a single file instead of serving `paths`; it is
+    /// fetched on first request and served from a temp copy afterwards
+    pub proxy: Option<String>,
+    /// Print nothing but errors; the banner, QR code and progress bars are skipped
+    pub quiet: bool,
+    /// Print just the share's URL on one line in place of the banner
+    pub print_url: bool,
+    /// Print a one-line summary of uptime, downloads and bytes served this often
+    pub status_interval: Option<Duration>,
+    /// Abort a download once its client has read nothing for this long
+    pub stall_timeout: Option<Duration>,
+    /// Keep full downloads open and send bytes appended to the file, like `tail -f`
+    pub follow: bool,
+    /// Under `follow`, end a download once the file has not grown for this long
+    pub follow_timeout: Option<Duration>,
+    /// Check everything and print what would be served, then return without serving
+    pub dry_run: bool,
+    /// Serve over HTTPS, with a self-signed certificate unless `cert` and `key` are set
+    pub tls: bool,
+    /// PEM certificate chain for TLS
+    pub cert: Option<PathBuf>,
+    /// PEM private key for TLS
+    pub key: Option<PathBuf>,
+    /// Under `tls`, only accept clients presenting a certificate signed by a
+    /// CA in this PEM file
+    pub client_ca: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            paths: Vec::new(),
+            manifest: None,
+            allow_special: false,
+            serve_symlinks: false,
+            route: "/download".to_string(),
+            download_name: None,
+            content_type: None,
+            port: 3000,
+            port_range: None,
+            bind: None,
+            ipv6: false,
+            dual_stack: false,
+            interface: None,
+            #[cfg(unix)]
+            unix: None,
+            parallel_downloads: 10,
+            inline: false,
+            hint: None,
+            qr: true,
+            qr_png: None,
+            open: false,
+            hostname: "wifi-file-server".to_string(),
+            auth: None,
+            password_file: None,
+            token: None,
+            limit_rate: None,
+            throttle_after: 0,
+            chunk_size: 4 * 1024,
+            no_ranges: false,
+            zip: false,
+            checksum: false,
+            hash_threshold: 1 << 30,
+            checksum_file: false,
+            basename_only: false,
+            security_headers: true,
+            cors: false,
+            cors_origins: Vec::new(),
+            cache_control: "no-cache".to_string(),
+            watch: false,
+            upload: None,
+            max_upload_size: None,
+            resumable: false,
+            upload_conflict: UploadConflict::Rename,
+            timeout: None,
+            max_downloads: None,
+            delete_when_done: false,
+            webhook: None,
+            max_connections: None,
+            rate_limit: None,
+            allow: Vec::new(),
+            proxy: None,
+            quiet: false,
+            print_url: false,
+            status_interval: None,
+            stall_timeout: None,
+            follow: false,
+            follow_timeout: None,
+            dry_run: false,
+            tls: false,
+            cert: None,
+            key: None,
+            client_ca: None,
+        }
+    }
+}
+
+/// A user name and password for HTTP Basic authentication. `pass` may also
+/// be a bcrypt hash of the password.
+#[derive(Clone, Debug)]
+pub struct Credentials {
+    pub user: String,
+    pub pass: String,
+}
+
+/// How an upload is saved when its name is already taken in the upload folder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UploadConflict {
+    /// Save it as `name-1.ext`, `name-2.ext`, ... instead
+    Rename,
+    /// Overwrite the existing file
+    Replace,
+    /// Reject the upload with 409 Conflict
+    Refuse,
+}
+
+/// The shell a suggested download command is written for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DownloadHint {
+    /// `curl -OJ`, for Unix shells and `curl.exe` on Windows
+    Curl,
+    /// `Invoke-WebRequest -OutFile`, since `curl` in Windows PowerShell is an
+    /// alias for it with different options
+    PowerShell,
+}
+
+// ==========================
+// Application State
+// ==========================
+#[derive(Clone)]
+struct AppState {
+    /// Public URL of the share, including `path_prefix`
+    host: String,
+    /// `/<token>` when `--token` is set, otherwise empty; prepended to every link
+    path_prefix: String,
+    parallel_downloads: u16,
+    root: Root,
+    inline: bool,
+    security_headers: bool,
+    basename_only: bool,
+    allow_special: bool,
+    serve_symlinks: bool,
+    cache_control: HeaderValue,
+    download_name: Option<String>,
+    content_type: Option<mime_guess::Mime>,
+    /// Where `download_handler` is mounted, below `path_prefix`
+    download_route: String,
+    hint: Option<DownloadHint>,
+    /// Accepted Basic auth users; empty means no authentication
+    users: Arc<[Credentials]>,
+    /// Digests of `user:pass` pairs already checked against a bcrypt hash, so
+    /// the deliberately slow hash runs once per user rather than per request
+    verified: Arc<Mutex<HashSet<[u8; 32]>>>,
+    self_signed: bool,
+    limit_rate: Option<u64>,
+    throttle_after: u64,
+    stall_timeout: Option<Duration>,
+    follow: bool,
+    follow_timeout: Option<Duration>,
+    chunk_size: usize,
+    no_ranges: bool,
+    checksum: bool,
+    hash_threshold: u64,
+    checksums: Arc<Mutex<HashMap<PathBuf, [u8; 32]>>>,
+    hashing: Arc<Mutex<HashSet<PathBuf>>>,
+    upload_dir: Option<PathBuf>,
+    max_upload_size: Option<u64>,
+    upload_conflict: UploadConflict,
+    /// Resumable uploads with a PATCH in flight, so two can't append at once
+    appending: Arc<Mutex<HashSet<Uuid>>>,
+    max_downloads: Option<u64>,
+    webhook: Option<Arc<Webhook>>,
+    quiet: bool,
+    connections: Option<Arc<Semaphore>>,
+    throttle: Option<Arc<Throttle>>,
+    /// Networks from `--allow`; empty lets every client in
+    allow: Arc<[IpNet]>,
+    started: Instant,
+    progress: Option<MultiProgress>,
+    completed_downloads: Arc<AtomicU64>,
+    /// Body bytes handed to clients across all downloads, for `/stats`
+    bytes_served: Arc<AtomicU64>,
+    /// Download bodies currently being sent, for `/stats`
+    active_downloads: Arc<AtomicU64>,
+    shutdown: CancellationToken,
+    download_cache: Arc<Mutex<LruCache<String, Vec<String>>>>,
+}
+
+impl AppState {
+    /// Counts a finished transfer and triggers shutdown once `--max-downloads` is reached.
+    fn record_download(&self) {
+        let completed = self.completed_downloads.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.max_downloads.is_some_and(|max| completed >= max) && !self.shutdown.is_cancelled() {
+            if !self.quiet {
+                println!("Download limit reached, exiting.");
+            }
+            self.shutdown.cancel();
+        }
+    }
+
+    /// `Root::locate` plus the `--basename-only` rule: a request may only name
+    /// a top-level file, so separators and folders are rejected with 400.
+    /// Special files get 403 unless `--allow-special` is set, and the partial
+    /// upload folder is 404.
+    fn locate(&self, path: &str) -> Result<PathBuf, StatusCode> {
+        let name = path.trim_matches('/');
+        // Uploads still being written are nobody's to download yet
+        if name.split(['/', '\\']).any(|part| part == PARTIAL_DIR) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        if self.basename_only && (name.contains(['/', '\\']) || name == "..") {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        let full_path = self.root.locate(path, self.serve_symlinks)?;
+        if self.basename_only && !name.is_empty() && full_path.is_dir() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        // A pipe or device inside a shared folder could stream forever
+        if !self.allow_special && !full_path.is_file() && !full_path.is_dir() {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(full_path)
+    }
+
+    /// Reserves a download slot under `--max-connections`; fails when all are busy.
+    fn acquire_connection(&self) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        match &self.connections {
+            Some(connections) => connections.clone().try_acquire_owned().map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// What the server exposes at `/`.
+#[derive(Clone)]
+enum Root {
+    /// A single file or directory given on the command line
+    Path(PathBuf),
+    /// Several paths, each listed at the top level under a unique name
+    Multi(Arc<BTreeMap<String, PathBuf>>),
+    /// A remote resource reshared as if it were a single file
+    Remote(Arc<RemoteFile>),
+    /// The files of a `--manifest`, each at its own route
+    Manifest(Arc<[ManifestFile]>),
+}
+
+/// One `[[file]]` entry of a `--manifest`, resolved and checked.
+struct ManifestFile {
+    route: String,
+    path: PathBuf,
+    /// Download filename to offer instead of the on-disk name
+    name: Option<String>,
+    /// Users accepted for this file alone; empty falls back to the share's own
+    users: Arc<[Credentials]>,
+}
+
+/// A `--manifest` file as written. Paths are relative to the manifest itself.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    file: Vec<ManifestEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestEntry {
+    path: PathBuf,
+    route: String,
+    name: Option<String>,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl Root {
+    /// Maps a URL path onto the filesystem, returning the base directory the
+    /// result must stay within alongside the joined (not yet canonical) path.
+    fn resolve(&self, path: &str) -> Option<(&Path, PathBuf)> {
+        let rel = sanitize_path(path);
+        let (base, rest) = match self {
+            Root::Path(root) => (root, rel),
+            Root::Multi(entries) => {
+                let mut components = rel.components();
+                let name = components.next()?.as_os_str().to_str()?;
+                (entries.get(name)?, components.as_path().to_path_buf())
+            }
+            Root::Remote(_) | Root::Manifest(_) => return None,
+        };
+        // Joining an empty path would add a trailing slash, which breaks file entries
+        let joined = if rest.as_os_str().is_empty() {
+            base.clone()
+        } else {
+            base.join(rest)
+        };
+        Some((base, joined))
+    }
+
+    /// Resolves a URL path to a canonical path that is guaranteed to lie
+    /// inside the served root. Unless `follow_symlinks` is set, a path that
+    /// passes through a symlink below the root is refused outright.
+    fn locate(&self, path: &str, follow_symlinks: bool) -> Result<PathBuf, StatusCode> {
+        let (base, joined) = self.resolve(path).ok_or(StatusCode::NOT_FOUND)?;
+
+        if !follow_symlinks && let Some(link) = first_symlink(base, &joined) {
+            info!(path = %link.display(), "Skipping symlink; pass --serve-symlinks to follow it");
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        // Canonicalize so symlinks cannot lead outside the served root
+        let full_path = joined.canonicalize().map_err(|_| StatusCode::NOT_FOUND)?;
+        if full_path.starts_with(base) {
+            Ok(full_path)
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+
+    /// Whether the share is one file, offered at the download route.
+    fn is_single_file(&self) -> bool {
+        match self {
+            Root::Path(path) => !path.is_dir(),
+            Root::Multi(_) | Root::Manifest(_) => false,
+            Root::Remote(_) => true,
+        }
+    }
+
+    /// The local paths given on the command line; empty for a remote share.
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Root::Path(path) => vec![path.as_path()],
+            Root::Multi(entries) => entries.values().map(PathBuf::as_path).collect(),
+            Root::Remote(_) => Vec::new(),
+            Root::Manifest(files) => files.iter().map(|f| f.path.as_path()).collect(),
+        }
+    }
+
+    /// The folders whose trees are published by `/download.zip` and
+    /// `/manifest`, each paired with the name it appears under.
+    fn folder_roots(&self) -> Option<Vec<(String, PathBuf)>> {
+        match self {
+            Root::Path(dir) if dir.is_dir() => Some(vec![(String::new(), dir.clone())]),
+            Root::Multi(entries) => Some(
+                entries
+                    .iter()
+                    .map(|(name, path)| (name.clone(), path.clone()))
+                    .collect(),
+            ),
+            Root::Path(_) | Root::Remote(_) | Root::Manifest(_) => None,
+        }
+    }
+
+    /// The manifest entry mounted at `route`, if any.
+    fn manifest_file(&self, route: &str) -> Option<&ManifestFile> {
+        match self {
+            Root::Manifest(files) => files.iter().find(|f| f.route == route),
+            _ => None,
+        }
+    }
+}
+
+/// The first symlink met walking from `base` down to `path`, which lies below it.
+fn first_symlink(base: &Path, path: &Path) -> Option<PathBuf> {
+    let rest = path.strip_prefix(base).ok()?;
+    let mut current = base.to_path_buf();
+    for component in rest.components() {
+        current.push(component);
+        if std::fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Some(current);
+        }
+    }
+    None
+}
+
+#[derive(Deserialize)]
+struct SelectionRequest {
+    files: Vec<String>,
+    dirs: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SelectionResponse {
+    id: String,
+}
+
+#[derive(Serialize)]
+struct UploadResponse {
+    files: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    sort: ListSort,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ListSort {
+    #[default]
+    Name,
+    Size,
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    name: String,
+    /// Host-relative link to the entry, ready to fetch
+    url: String,
+    is_dir: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mime: Option<String>,
+}
+
+/// One file of `GET /manifest`.
+#[derive(Serialize)]
+struct SyncEntry {
+    /// Slash-separated path below the share's root
+    path: String,
+    /// Host-relative download link, percent-encoded
+    url: String,
+    size: u64,
+    /// `None` for files above `--hash-threshold`, which are never hashed here
+    sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    completed_downloads: u64,
+    bytes_served: u64,
+    active_downloads: u64,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: &'static str,
+    uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+struct FileInfo {
+    name: String,
+    size: u64,
+    mime: String,
+    etag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+}
+
+// ==========================
+// Entry Point
+// ==========================
+/// Serves `config` until Ctrl-C, `--timeout` or `--max-downloads` stops it,
+/// letting in-flight downloads finish first. Startup problems such as an
+/// unusable path or a busy port are returned as errors.
+pub async fn run(config: ServerConfig) -> io::Result<()> {
+    start(config, None).await
+}
+
+/// Like [`run`], but serves on `listener` instead of binding one from the
+/// address options. Binding port 0 and reading `local_addr` first gives tests
+/// a server on a free port.
+pub async fn run_on(config: ServerConfig, listener: TcpListener) -> io::Result<()> {
+    start(config, Some(listener)).await
+}
+
+async fn start(config: ServerConfig, bound: Option<TcpListener>) -> io::Result<()> {
+    check_route(&config.route, "the download route")?;
+
+    let root = match (&config.proxy, &config.manifest) {
+        (Some(url), _) => Root::Remote(Arc::new(RemoteFile::new(url)?)),
+        (None, Some(path)) => Root::Manifest(load_manifest(path, &config)?.into()),
+        (None, None) => resolve_root(&config)?,
+    };
+
+    let cors = if config.cors {
+        Some(cors_layer(&config.cors_origins)?)
+    } else {
+        None
+    };
+
+    let content_type = config
+        .content_type
+        .as_deref()
+        .map(|value| {
+            HeaderValue::from_str(value)
+                .ok()
+                .and(value.parse::<mime_guess::Mime>().ok())
+                .ok_or_else(|| io::Error::other(format!("{:?} is not a valid Content-Type", value)))
+        })
+        .transpose()?;
+
+    let webhook = config.webhook.as_deref().map(Webhook::new).transpose()?;
+
+    let mut users: Vec<Credentials> = config.auth.iter().cloned().collect();
+    if let Some(path) = &config.password_file {
+        users.extend(load_password_file(path)?);
+    }
+
+    let cache_control = HeaderValue::from_str(&config.cache_control).map_err(|e| {
+        context(
+            e,
+            format!("Could not use {:?} as Cache-Control", config.cache_control),
+        )
+    })?;
+
+    #[cfg(unix)]
+    let unix = config.unix.clone();
+    #[cfg(not(unix))]
+    let unix: Option<PathBuf> = None;
+
+    // `advertised` is None on a Unix socket, which has no address to put in URLs,
+    // and `advertised_v6` is only set for --dual-stack. A dry run still binds TCP
+    // to prove the port is free, but never creates a socket file
+    let mut advertised_v6 = None;
+    let (listener, advertised) = match unix {
+        #[cfg(unix)]
+        Some(path) if config.dry_run => {
+            let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+            if parent.is_some_and(|p| !p.is_dir()) {
+                return Err(io::Error::other(format!(
+                    "Could not bind to {}. Details: parent folder does not exist",
+                    path.display()
+                )));
+            }
+            (None, None)
+        }
+        #[cfg(unix)]
+        Some(path) => (Some(Listener::Unix(bind_unix(&path)?, path)), None),
+        _ => {
+            let (listener, addr) = match bound {
+                Some(listener) => {
+                    let addr = listener.local_addr()?;
+                    (listener, addr)
+                }
+                None => bind_tcp(&config).await?,
+            };
+            if config.dual_stack {
+                let (v6, addr_v6) = bind_dual_stack_v6(&config, addr.port())?;
+                advertised_v6 = addr_v6;
+                (Some(Listener::DualStack(listener, v6)), Some(addr))
+            } else {
+                (Some(Listener::Tcp(listener)), Some(addr))
+            }
+        }
+    };
+
+    let tls = match advertised {
+        Some(addr) if config.tls => Some(
+            load_tls(
+                config.cert.as_deref(),
+                config.key.as_deref(),
+                std::iter::once(addr)
+                    .chain(advertised_v6)
+                    .map(|a| a.ip().to_string())
+                    .collect(),
+                config.client_ca.as_deref(),
+            )
+            .await
+            .map_err(|e| context(e, "Could not set up TLS".to_string()))?,
+        ),
+        _ => None,
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
+
+    let path_prefix = config
+        .token
+        .as_ref()
+        .map(|token| format!("/{}", token))
+        .unwrap_or_default();
+    // SocketAddr's Display brackets IPv6 addresses as URLs require. Behind a
+    // Unix socket the proxy decides the public URL, so links stay host-relative
+    let host = match advertised {
+        Some(addr) => format!("{}://{}{}", scheme, addr, path_prefix),
+        None => format!("{}://localhost{}", scheme, path_prefix),
+    };
+    let host_v6 = advertised_v6.map(|addr| format!("{}://{}{}", scheme, addr, path_prefix));
+
+    let listener = match listener {
+        Some(listener) if !config.dry_run => listener,
+        _ => {
+            print_plan(
+                &config,
+                &root,
+                &host,
+                host_v6.as_deref(),
+                tls.as_ref(),
+                &users,
+            );
+            return Ok(());
+        }
+    };
+
+    let upload_dir = match &config.upload {
+        Some(dir) => Some(
+            std::fs::create_dir_all(dir)
+                .and_then(|_| dir.canonicalize())
+                .map_err(|e| {
+                    context(e, format!("Could not use upload folder {}", dir.display()))
+                })?,
+        ),
+        None => None,
+    };
+
+    let single_file = root.is_single_file();
+
+    // --name ends up in shell commands as well as headers, so clean it once here
+    let download_name = config
+        .download_name
+        .as_deref()
+        .map(sanitize_filename)
+        .filter(|name| !name.is_empty());
+
+    let state = AppState {
+        host: host.clone(),
+        path_prefix: path_prefix.clone(),
+        root: root.clone(),
+        parallel_downloads: config.parallel_downloads,
+        inline: config.inline,
+        security_headers: config.security_headers,
+        basename_only: config.basename_only,
+        allow_special: config.allow_special,
+        serve_symlinks: config.serve_symlinks,
+        cache_control,
+        download_name: download_name.clone(),
+        content_type: content_type.clone(),
+        download_route: config.route.clone(),
+        hint: config.hint,
+        users: users.into(),
+        verified: Arc::new(Mutex::new(HashSet::new())),
+        self_signed: tls.as_ref().is_some_and(|t| t.self_signed),
+        limit_rate: config.limit_rate,
+        throttle_after: config.throttle_after,
+        stall_timeout: config.stall_timeout,
+        follow: config.follow,
+        follow_timeout: config.follow_timeout,
+        chunk_size: config.chunk_size,
+        no_ranges: config.no_ranges,
+        checksum: config.checksum,
+        hash_threshold: config.hash_threshold,
+        checksums: Arc::new(Mutex::new(HashMap::new())),
+        hashing: Arc::new(Mutex::new(HashSet::new())),
+        upload_dir: upload_dir.clone(),
+        max_upload_size: config.max_upload_size,
+        upload_conflict: config.upload_conflict,
+        appending: Arc::new(Mutex::new(HashSet::new())),
+        max_downloads: config.max_downloads,
+        webhook: webhook.map(Arc::new),
+        quiet: config.quiet,
+        connections: config
+            .max_connections
+            .map(|n| Arc::new(Semaphore::new(n as usize))),
+        throttle: config.rate_limit.map(|n| Arc::new(Throttle::new(n))),
+        allow: config.allow.clone().into(),
+        started: Instant::now(),
+        // Progress bars only make sense for someone watching the terminal
+        progress: (!config.quiet && io::stdout().is_terminal()).then(MultiProgress::new),
+        completed_downloads: Arc::new(AtomicU64::new(0)),
+        bytes_served: Arc::new(AtomicU64::new(0)),
+        active_downloads: Arc::new(AtomicU64::new(0)),
+        shutdown: CancellationToken::new(),
+        download_cache: Arc::new(Mutex::new(LruCache::new(
+            std::num::NonZeroUsize::new(100).unwrap(),
+        ))),
+    };
+
+    // Written before serving so the sidecar is in place by the time anyone downloads
+    let mut sidecars = Vec::new();
+    if config.checksum_file {
+        for file in state.root.paths().into_iter().filter(|f| f.is_file()) {
+            let sidecar = write_checksum_file(&state, file).await.map_err(|e| {
+                context(
+                    e,
+                    format!("Could not write a checksum file for {}", file.display()),
+                )
+            })?;
+            sidecars.push(sidecar);
+        }
+    }
+
+    // Hash the shared files up front so the first download already carries a Digest,
+    // unless that would mean minutes of disk churn the user did not ask for
+    if config.checksum {
+        for file in state.root.paths().into_iter().filter(|f| f.is_file()) {
+            let size = std::fs::metadata(file).map_or(0, |m| m.len());
+            if size <= config.hash_threshold {
+                spawn_checksum(&state, file);
+            } else if !config.quiet {
+                eprintln!(
+                    "Warning: {} is {}, above --hash-threshold, so it is not hashed automatically; GET /checksum still computes it on request",
+                    file.display(),
+                    format_size(size)
+                );
+            }
+        }
+    }
+
+    // Kept alive for the lifetime of the server; dropping it stops watching
+    let _watcher = if config.watch {
+        Some(
+            watch_root(&state)
+                .map_err(|e| context(e, "Could not watch the shared paths".to_string()))?,
+        )
+    } else {
+        None
+    };
+
+    let shutdown = state.shutdown.clone();
+
+    if let Some(timeout) = config.timeout {
+        let shutdown = shutdown.clone();
+        let quiet = config.quiet;
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if !shutdown.is_cancelled() {
+                if !quiet {
+                    println!("Timeout reached, exiting.");
+                }
+                shutdown.cancel();
+            }
+        });
+    }
+
+    let app = build_app(state.clone(), &config, cors);
+
+    let terminated = listen_for_sigterm();
+
+    // --print-url replaces the banner with a single line scripts can read
+    let banner = !config.quiet && !config.print_url;
+    if config.print_url {
+        if single_file {
+            println!("{}{}", host, config.route);
+        } else if config.zip {
+            println!("{}/download.zip", host);
+        } else {
+            println!("{}", host);
+        }
+    }
+    if banner {
+        match &listener {
+            Listener::Tcp(_) => println!("Server running at {}", host),
+            Listener::DualStack(..) => {
+                println!("Server running at {}", host);
+                match &host_v6 {
+                    Some(host_v6) => println!("Also running at {}", host_v6),
+                    None => println!("Also listening on all IPv6 interfaces"),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(_, path) => println!("Server listening on {}", path.display()),
+        }
+        if let Some(timeout) = config.timeout {
+            println!(
+                "Server will stop at {}",
+                httpdate::fmt_http_date(SystemTime::now() + timeout)
+            );
+        }
+        if single_file {
+            println!("Download URL: {}{}", host, config.route);
+            let local = if cfg!(windows) {
+                DownloadHint::PowerShell
+            } else {
+                DownloadHint::Curl
+            };
+            let name = match (&download_name, &root) {
+                (Some(name), _) => name.clone(),
+                (None, Root::Path(path)) => file_display_name(path, "download"),
+                (None, _) => "download".to_string(),
+            };
+            let url = format!("{}{}", host, config.route);
+            let insecure = tls.as_ref().is_some_and(|t| t.self_signed);
+            println!(
+                "Download command: {}",
+                download_command(config.hint.unwrap_or(local), &url, &name, insecure)
+            );
+        } else if config.zip {
+            println!("Archive URL: {}/download.zip", host);
+        }
+        if let Root::Manifest(files) = &root {
+            for file in files.iter() {
+                println!("File URL: {}{}", host, file.route);
+            }
+        }
+        if let Some(tls) = &tls {
+            println!("Certificate SHA-256 fingerprint: {}", tls.fingerprint);
+            if tls.self_signed {
+                println!(
+                    "Note: the certificate is self-signed, so browsers will show a warning and curl needs -k"
+                );
+            }
+        }
+        if let Some(ca) = &config.client_ca {
+            println!(
+                "Clients need a certificate signed by {}, e.g. curl --cert <pem> --key <pem>",
+                ca.display()
+            );
+        }
+        for (path, digest) in &sidecars {
+            println!("SHA-256 {} written to {}", digest, path.display());
+        }
+        if let Some(dir) = &upload_dir {
+            println!(
+                "Uploads accepted at {}/upload (saved to {}), from a browser or e.g. curl -F file=@<path> {}/upload",
+                host,
+                dir.display(),
+                host
+            );
+            if config.resumable {
+                println!("Resumable (tus) uploads accepted at {}/uploads", host);
+            }
+        }
+    }
+    let mut mdns = None;
+    if let Some(addr) = advertised {
+        // Nobody else on the network could reach a loopback address
+        if !addr.ip().is_loopback() {
+            match advertise_mdns(&config.hostname, addr.ip(), addr.port()) {
+                Ok(advert) => {
+                    if banner {
+                        println!(
+                            "Also reachable at {}://{}.local:{}{}",
+                            scheme,
+                            config.hostname,
+                            addr.port(),
+                            path_prefix
+                        );
+                    }
+                    mdns = Some(advert);
+                }
+                Err(e) if !config.quiet => {
+                    eprintln!("Warning: Could not advertise over mDNS. Error: {}", e)
+                }
+                Err(_) => {}
+            }
+        }
+        if config.qr && banner {
+            print_qr(&host);
+        }
+        if let Some(path) = &config.qr_png {
+            match write_qr_png(&host, path) {
+                Ok(()) if banner => println!("QR code saved to {}", path.display()),
+                Ok(()) => {}
+                Err(e) if !config.quiet => {
+                    eprintln!("Warning: Could not write QR code image. Error: {}", e)
+                }
+                Err(_) => {}
+            }
+        }
+
+        if config.open
+            && let Err(e) = open::that_detached(format!("{}/", host))
+            && !config.quiet
+        {
+            eprintln!("Warning: Could not open a browser. Error: {}", e);
+        }
+    }
+
+    // Cancelled only by --max-downloads or --timeout, never by Ctrl-C
+    let limit_reached = shutdown.clone();
+    // One signal stops every listener, so both halves of --dual-stack drain together
+    let stop = CancellationToken::new();
+    {
+        let stop = stop.clone();
+        let quiet = config.quiet;
+        tokio::spawn(async move {
+            // Dropped even if printing the notice panics, e.g. on a closed stdout
+            let _stop = stop.drop_guard();
+            shutdown_signal(shutdown, terminated, quiet).await;
+        });
+    }
+    if let Some(period) = config.status_interval.filter(|p| !p.is_zero())
+        && !config.quiet
+    {
+        tokio::spawn(print_status(state, period, stop.clone()));
+    }
+    let tls = tls.map(|tls| tls.config);
+    let served = match listener {
+        Listener::Tcp(listener) => serve_tcp(listener, tls, app, stop).await,
+        Listener::DualStack(v4, v6) => {
+            let (v4, v6) = tokio::join!(
+                serve_tcp(v4, tls.clone(), app.clone(), stop.clone()),
+                serve_tcp(v6, tls, app, stop)
+            );
+            v4.and(v6)
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener, path) => {
+            let served = axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<ClientAddr>(),
+            )
+            .with_graceful_shutdown(stop.cancelled_owned())
+            .await;
+            let _ = std::fs::remove_file(&path);
+            served
+        }
+    };
+
+    if let Some(mdns) = mdns {
+        mdns.stop();
+    }
+    if let Root::Remote(remote) = &root {
+        let _ = std::fs::remove_dir_all(&remote.dir);
+    }
+    if config.delete_when_done && limit_reached.is_cancelled() && served.is_ok() {
+        delete_shared_files(&root);
+    }
+    served
+}
+
+/// Assembles every route and middleware layer for `state`.
+fn build_app(state: AppState, config: &ServerConfig, cors: Option<CorsLayer>) -> Router {
+    let mut app = Router::new()
+        .route("/", get(root_handler))
+        .route(&config.route, get(download_handler).head(download_handler))
+        .route("/files/", get(root_handler))
+        .route("/files/{*path}", get(file_handler))
+        .route("/register-selection", post(register_selection))
+        .route("/config/{id}", get(config_handler))
+        .route("/checksum", get(root_checksum_handler))
+        .route("/checksum/{*path}", get(checksum_handler))
+        .route("/info", get(root_info_handler))
+        .route("/info/{*path}", get(info_handler))
+        .route("/list", get(root_list_handler))
+        .route("/list/{*path}", get(list_handler))
+        .route("/stats", get(stats_handler))
+        .route("/manifest", get(sync_manifest_handler));
+
+    if let Root::Manifest(files) = &state.root {
+        for file in files.iter() {
+            app = app.route(&file.route, get(manifest_handler).head(manifest_handler));
+        }
+    }
+
+    if config.zip {
+        app = app.route("/download.zip", get(zip_handler));
+    }
+
+    if state.upload_dir.is_some() {
+        // Uploads can be far larger than axum's default body limit
+        let limit = match config.max_upload_size {
+            Some(max) => DefaultBodyLimit::max(usize::try_from(max).unwrap_or(usize::MAX)),
+            None => DefaultBodyLimit::disable(),
+        };
+        app = app.route(
+            "/upload",
+            get(upload_page).post(upload_handler).layer(limit),
+        );
+        if config.resumable {
+            app = app
+                .route("/uploads", post(create_upload).options(tus_options))
+                .route("/uploads/{id}", head(upload_offset).patch(append_upload));
+        }
+    }
+
+    // With a token every route lives under it and any other path is a plain 404
+    if !state.path_prefix.is_empty() {
+        app = Router::new()
+            .nest(&state.path_prefix, app)
+            .route(&format!("{}/", state.path_prefix), get(root_handler));
+    }
+
+    let mut app = app
+        .route("/health", get(health_handler))
+        .fallback(fallback_handler)
+        .layer(middleware::from_fn_with_state(state.clone(), require_auth))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            throttle_requests,
+        ))
+        .layer(middleware::from_fn_with_state(state.clone(), allow_clients))
+        .layer(middleware::from_fn(log_request));
+    if config.security_headers {
+        app = app.layer(middleware::from_fn(security_headers));
+    }
+    // Outside auth so browsers' credential-less preflight requests get an answer
+    if let Some(cors) = cors {
+        app = app.layer(cors);
+    }
+    app.layer(CompressionLayer::new().compress_when(compress_predicate()))
+        .with_state(state)
+}
+
+/// Removes the shared files for `--max-age-delete`. Folders are never deleted,
+/// only files given on the command line, and each removal is logged.
+fn delete_shared_files(root: &Root) {
+    for path in root.paths() {
+        if !path.is_file() {
+            warn!(path = %path.display(), "Not a file, leaving it in place");
+            continue;
+        }
+        match std::fs::remove_file(path) {
+            Ok(()) => info!(file = %path.display(), "Deleted shared file"),
+            Err(e) => error!(file = %path.display(), error = %e, "Could not delete shared file"),
+        }
+    }
+}
+
+/// Canonicalizes the shared paths (the current directory when none are given)
+/// into what `/` serves.
+fn resolve_root(config: &ServerConfig) -> io::Result<Root> {
+    let mut paths = config.paths.clone();
+    if paths.is_empty() {
+        paths.push(env::current_dir()?);
+    }
+    let mut resolved = Vec::with_capacity(paths.len());
+    for path in paths {
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| context(e, format!("Could not open {}", path.display())))?;
+        let file_type = std::fs::metadata(&canonical)
+            .map_err(|e| context(e, format!("Could not open {}", path.display())))?
+            .file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            let kind = special_file_kind(&file_type);
+            if !config.allow_special {
+                return Err(io::Error::other(format!(
+                    "{} is a {}, not a regular file. Pass --allow-special to stream it anyway",
+                    path.display(),
+                    kind
+                )));
+            }
+            if kind == "named pipe" && !config.quiet {
+                eprintln!(
+                    "Warning: {} is a named pipe, so its length is unknown and each download reads what is written to it",
+                    path.display()
+                );
+            }
+        }
+        if !file_type.is_dir() && canonical.file_name().is_none() {
+            return Err(io::Error::other(format!(
+                "Could not derive a file name from {}",
+                path.display()
+            )));
+        }
+        resolved.push(canonical);
+    }
+    let paths = resolved;
+
+    // Normal runs find unreadable files at request time; a dry run checks up front
+    if config.dry_run {
+        for path in &paths {
+            // Opening a pipe would wait for a writer, so special files are not probed
+            let readable = if path.is_dir() {
+                std::fs::read_dir(path).map(|_| ())
+            } else if path.is_file() {
+                std::fs::File::open(path).map(|_| ())
+            } else {
+                Ok(())
+            };
+            readable.map_err(|e| context(e, format!("Could not read {}", path.display())))?;
+        }
+    }
+
+    Ok(if paths.len() == 1 {
+        Root::Path(paths.into_iter().next().unwrap())
+    } else {
+        Root::Multi(Arc::new(name_entries(paths)))
+    })
+}
+
+/// Reads a `--manifest`, checking every entry the way `resolve_root` and
+/// `check_route` check the command line, so mistakes surface at startup.
+fn load_manifest(path: &Path, config: &ServerConfig) -> io::Result<Vec<ManifestFile>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| context(e, format!("Could not read {}", path.display())))?;
+    let manifest: Manifest = toml::from_str(&contents)
+        .map_err(|e| context(e, format!("Could not parse {}", path.display())))?;
+    if manifest.file.is_empty() {
+        return Err(io::Error::other(format!(
+            "{} lists no [[file]] entries",
+            path.display()
+        )));
+    }
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut files: Vec<ManifestFile> = Vec::with_capacity(manifest.file.len());
+    for entry in manifest.file {
+        let problem = |details: String| {
+            io::Error::other(format!(
+                "{}: the entry for {} {}",
+                path.display(),
+                entry.route,
+                details
+            ))
+        };
+        check_route(&entry.route, "a manifest route")?;
+        if entry.route == config.route || files.iter().any(|f| f.route == entry.route) {
+            return Err(problem("uses a route that is already taken".to_string()));
+        }
+
+        let file = base.join(&entry.path);
+        let canonical = file
+            .canonicalize()
+            .map_err(|e| problem(format!("could not open {}: {}", file.display(), e)))?;
+        if canonical.is_dir() {
+            return Err(problem(format!("names a folder, {}", file.display())));
+        }
+        if !canonical.is_file() && !config.allow_special {
+            return Err(problem(format!(
+                "names a special file, {}. Pass --allow-special to stream it anyway",
+                file.display()
+            )));
+        }
+
+        let users = match (entry.user, entry.password) {
+            (Some(user), Some(pass)) => vec![Credentials { user, pass }],
+            (None, None) => Vec::new(),
+            _ => {
+                return Err(problem(
+                    "needs both user and password, or neither".to_string(),
+                ));
+            }
+        };
+        files.push(ManifestFile {
+            route: entry.route,
+            path: canonical,
+            name: entry
+                .name
+                .as_deref()
+                .map(sanitize_filename)
+                .filter(|name| !name.is_empty()),
+            users: users.into(),
+        });
+    }
+    Ok(files)
+}
+
+/// Names what a non-regular, non-folder path is, for error messages.
+fn special_file_kind(file_type: &std::fs::FileType) -> &'static str {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        if file_type.is_fifo() {
+            return "named pipe";
+        } else if file_type.is_char_device() {
+            return "character device";
+        } else if file_type.is_block_device() {
+            return "block device";
+        } else if file_type.is_socket() {
+            return "socket";
+        }
+    }
+    let _ = file_type;
+    "special file"
+}
+
+/// Paths the router already uses, which `--route` must not take over.
+const RESERVED_ROUTES: &[&str] = &[
+    "/",
+    "/files/",
+    "/register-selection",
+    "/checksum",
+    "/info",
+    "/list",
+    "/stats",
+    "/manifest",
+    "/health",
+    "/download.zip",
+    "/upload",
+    "/uploads",
+];
+
+/// Checks that `route` is a plain absolute path the router can mount; `what`
+/// names it in the error.
+fn check_route(route: &str, what: &str) -> io::Result<()> {
+    let problem = if !route.starts_with('/') {
+        Some("it must start with /")
+    } else if route.contains(['{', '}', '?', '#', '*']) || route.contains("//") {
+        Some("it may not contain {, }, ?, #, * or //")
+    } else if RESERVED_ROUTES.contains(&route) {
+        Some("the server already uses that path")
+    } else {
+        None
+    };
+    match problem {
+        Some(problem) => Err(io::Error::other(format!(
+            "Could not use {} as {}. Details: {}",
+            route, what, problem
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Read-only CORS for `--cors`: GET and HEAD from `origins` (any when empty),
+/// with the headers scripts need for filenames and ranged fetches exposed.
+fn cors_layer(origins: &[String]) -> io::Result<CorsLayer> {
+    let allow_origin = if origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .map_err(|e| context(e, format!("Could not use {:?} as a CORS origin", origin)))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers([
+            header::RANGE,
+            header::IF_NONE_MATCH,
+            header::IF_MODIFIED_SINCE,
+            header::AUTHORIZATION,
+        ])
+        .expose_headers([
+            header::ACCEPT_RANGES,
+            header::CONTENT_DISPOSITION,
+            header::CONTENT_RANGE,
+            header::CONTENT_LENGTH,
+            header::ETAG,
+            header::LAST_MODIFIED,
+        ]))
+}
+
+/// Describes what `run` would serve, for `--dry-run`.
+fn print_plan(
+    config: &ServerConfig,
+    root: &Root,
+    host: &str,
+    host_v6: Option<&str>,
+    tls: Option<&TlsSetup>,
+    users: &[Credentials],
+) {
+    println!("Dry run: all checks passed, nothing is being served.");
+    if let Root::Remote(remote) = root {
+        println!("Proxying {}", remote.url);
+    }
+    if let Root::Manifest(files) = root {
+        for file in files.iter() {
+            let auth = if file.users.is_empty() {
+                ""
+            } else {
+                " (own credentials)"
+            };
+            println!(
+                "Sharing file: {} at {}{}{}",
+                file.path.display(),
+                host,
+                file.route,
+                auth
+            );
+        }
+    } else {
+        for path in root.paths() {
+            let kind = if path.is_dir() { "folder" } else { "file" };
+            println!("Sharing {}: {}", kind, path.display());
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.unix {
+        println!("Would listen on {}", path.display());
+    }
+    println!("Server URL: {}", host);
+    if let Some(host_v6) = host_v6 {
+        println!("IPv6 URL: {}", host_v6);
+    }
+    if root.is_single_file() {
+        println!("Download URL: {}{}", host, config.route);
+    } else if config.zip {
+        println!("Archive URL: {}/download.zip", host);
+    }
+
+    if let Some(tls) = tls {
+        println!("Certificate SHA-256 fingerprint: {}", tls.fingerprint);
+    }
+    if let Some(ca) = &config.client_ca {
+        println!("Client certificates signed by {} required", ca.display());
+    }
+    if config.checksum_file {
+        for path in root.paths().into_iter().filter(|p| p.is_file()) {
+            println!("Would write {}", checksum_file_path(path).display());
+        }
+    }
+    if let Some(content_type) = &config.content_type {
+        println!("Content-Type: {} for every file", content_type);
+    }
+    if !users.is_empty() {
+        let names: Vec<&str> = users.iter().map(|c| c.user.as_str()).collect();
+        println!("Basic auth: {}", names.join(", "));
+    }
+    if let Some(dir) = &config.upload {
+        let note = if dir.is_dir() {
+            ""
+        } else {
+            " (will be created)"
+        };
+        println!("Uploads saved to {}{}", dir.display(), note);
+        if config.resumable {
+            println!("Resumable (tus) uploads accepted at {}/uploads", host);
+        }
+    }
+    if let Some(rate) = config.limit_rate {
+        println!("Rate limit: {}/s per download", format_size(rate));
+        if config.throttle_after > 0 {
+            println!(
+                "The first {} of each download are not limited",
+                format_size(config.throttle_after)
+            );
+        }
+    }
+    if let Some(max) = config.max_connections {
+        println!("At most {} concurrent downloads", max);
+    }
+    if config.no_ranges {
+        println!("Range requests are ignored; files are always sent whole");
+    }
+    if !config.security_headers {
+        println!("Security headers are off; shared HTML files run script when opened");
+    }
+    if config.follow {
+        println!("Downloads follow their file as it grows, like tail -f");
+    }
+    if let Some(max) = config.rate_limit {
+        println!("At most {} requests per minute per client", max);
+    }
+    if !config.allow.is_empty() {
+        let networks: Vec<String> = config.allow.iter().map(|n| n.to_string()).collect();
+        println!("Only serving clients in {}", networks.join(", "));
+    }
+    if let Some(max) = config.max_downloads {
+        println!("Stops after {} completed downloads", max);
+    }
+    if config.delete_when_done {
+        println!("Deletes the shared files when it stops on its own");
+    }
+    if let Some(url) = &config.webhook {
+        println!("Reports completed downloads to {}", url);
+    }
+    if let Some(timeout) = config.timeout {
+        println!("Stops after {}s", timeout.as_secs());
+    }
+}
+
+/// Wraps `e` with what was being attempted, in the same shape as the
+/// messages printed at startup.
+fn context(e: impl fmt::Display, message: String) -> io::Error {
+    io::Error::other(format!("{}. Details: {}", message, e))
+}
+
+/// Binds the TCP listener from the address options and works out the
+/// address to advertise in URLs.
+async fn bind_tcp(config: &ServerConfig) -> io::Result<(TcpListener, SocketAddr)> {
+    let bind = config.bind.unwrap_or(if config.ipv6 {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    });
+
+    // Advertise the detected LAN address unless bound to one specific interface
+    let local_ip = if !bind.is_unspecified() {
+        bind
+    } else if let Some(name) = &config.interface {
+        interface_ip(name, bind.is_ipv6()).ok_or_else(|| {
+            io::Error::other(format!(
+                "No {} address found on interface {}. Use --list-interfaces to see the options.",
+                if bind.is_ipv6() { "IPv6" } else { "IPv4" },
+                name
+            ))
+        })?
+    } else if bind.is_ipv6() {
+        local_ip_address::local_ipv6().map_err(|e| {
+            context(
+                e,
+                "--ipv6 was given but no IPv6 address was found".to_string(),
+            )
+        })?
+    } else {
+        local_ip_address::local_ip().unwrap_or_else(|e| {
+            eprintln!(
+                "Warning: Could not determine local IP. Using 127.0.0.1. Error: {}",
+                e
+            );
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        })
+    };
+
+    let (first_port, last_port) = config.port_range.unwrap_or((config.port, config.port));
+    let listener = bind_listener(bind, first_port, last_port)
+        .await
+        .map_err(|e| {
+            if config.port_range.is_none() || e.kind() != io::ErrorKind::AddrInUse {
+                let hint = bind_hint(&e);
+                context(
+                    e,
+                    format!(
+                        "Could not bind to {}{}",
+                        SocketAddr::new(bind, first_port),
+                        hint
+                    ),
+                )
+            } else {
+                io::Error::other(format!(
+                    "No free port between {} and {} on {}. Last error: {}",
+                    first_port, last_port, bind, e
+                ))
+            }
+        })?;
+    let port = listener.local_addr().map_or(first_port, |a| a.port());
+    if config.port_range.is_some() && !config.quiet {
+        println!("Using port {}", port);
+    }
+
+    Ok((listener, SocketAddr::new(local_ip, port)))
+}
+
+/// A suggestion to follow a bind error with, for the failures people hit
+/// most: a port that is taken, or an address that left with the network.
+fn bind_hint(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::AddrInUse => {
+            " (the port is in use; pick another with --port or try several with --port-range)"
+        }
+        io::ErrorKind::AddrNotAvailable => {
+            " (that address is not on this machine; it may have changed with the network)"
+        }
+        io::ErrorKind::PermissionDenied => " (ports below 1024 usually need root)",
+        _ => "",
+    }
+}
+
+/// Binds the IPv6 half of `--dual-stack` on `port`, alongside the IPv4
+/// listener from `bind_tcp`, and finds an IPv6 address to advertise. Having
+/// none is only a warning: the listener still serves whoever reaches it.
+fn bind_dual_stack_v6(
+    config: &ServerConfig,
+    port: u16,
+) -> io::Result<(TcpListener, Option<SocketAddr>)> {
+    let bind = SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port);
+    let listener =
+        bind_v6_only(bind).map_err(|e| context(e, format!("Could not bind to {}", bind)))?;
+
+    let ip = match &config.interface {
+        Some(name) => interface_ip(name, true),
+        None => local_ip_address::local_ipv6().ok(),
+    };
+    if ip.is_none() && !config.quiet {
+        eprintln!(
+            "Warning: Could not determine a local IPv6 address, so only the IPv4 URL is shown"
+        );
+    }
+    Ok((listener, ip.map(|ip| SocketAddr::new(ip, port))))
+}
+
+/// Binds an IPv6 listener that leaves IPv4 alone. Linux and macOS otherwise
+/// accept IPv4-mapped connections on `[::]`, which takes the port from the
+/// IPv4 listener.
+fn bind_v6_only(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(true)?;
+    // Matches tokio's own bind, so a restarted server can take the port at once
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Serves `app` on a TCP listener, over TLS when `tls` is set, until `stop`
+/// is cancelled; open downloads are drained first.
+async fn serve_tcp(
+    listener: TcpListener,
+    tls: Option<RustlsConfig>,
+    app: Router,
+    stop: CancellationToken,
+) -> io::Result<()> {
+    let make_service = app.into_make_service_with_connect_info::<ClientAddr>();
+    match tls {
+        Some(tls) => {
+            let handle = axum_server::Handle::new();
+            let shutdown_handle = handle.clone();
+            tokio::spawn(async move {
+                stop.cancelled().await;
+                shutdown_handle.graceful_shutdown(None);
+            });
+
+            axum_server::from_tcp(listener.into_std()?)?
+                .acceptor(ClientCertLogger(RustlsAcceptor::new(tls)))
+                .handle(handle)
+                .serve(make_service)
+                .await
+        }
+        None => {
+            axum::serve(listener, make_service)
+                .with_graceful_shutdown(stop.cancelled_owned())
+                .await
+        }
+    }
+}
+
+/// Binds the first port between `first` and `last` (inclusive) that is free.
+/// Only a taken port moves on to the next one; any other error would repeat
+/// on every port, so it is returned straight away.
+async fn bind_listener(ip: IpAddr, first: u16, last: u16) -> io::Result<TcpListener> {
+    let mut last_error = None;
+    for port in first..=last {
+        match TcpListener::bind(SocketAddr::new(ip, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => last_error = Some(e),
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::other("empty port range")))
+}
+
+/// Binds a Unix domain socket, replacing a stale socket file left by an
+/// earlier run.
+#[cfg(unix)]
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        let _ = std::fs::remove_file(path);
+    }
+    UnixListener::bind(path)
+        .map_err(|e| context(e, format!("Could not bind to {}", path.display())))
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// `--dual-stack`: an IPv4 and an IPv6 listener on the same port
+    DualStack(TcpListener, TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// The peer of a request, as recorded in logs. Unix socket peers have no
+/// useful address, so they show up as `unix`.
+#[derive(Clone, Copy, Debug)]
+enum ClientAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix,
+}
+
+impl fmt::Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddr::Tcp(addr) => write!(f, "{}", addr.ip()),
+            #[cfg(unix)]
+            ClientAddr::Unix => f.write_str("unix"),
+        }
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for ClientAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        ClientAddr::Tcp(*stream.remote_addr())
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for ClientAddr {
+    fn connect_info(_: IncomingStream<'_, UnixListener>) -> Self {
+        ClientAddr::Unix
+    }
+}
+
+// axum-server hands over the peer address directly for TLS connections
+impl Connected<SocketAddr> for ClientAddr {
+    fn connect_info(addr: SocketAddr) -> Self {
+        ClientAddr::Tcp(addr)
+    }
+}
+
+/// Resolves on Ctrl-C, SIGTERM or when `shutdown` is cancelled; the server
+/// then stops accepting connections but lets in-flight downloads finish.
+async fn shutdown_signal(shutdown: CancellationToken, terminated: CancellationToken, quiet: bool) {
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            result.expect("Failed to install Ctrl-C handler");
+        }
+        _ = terminated.cancelled() => {}
+        _ = shutdown.cancelled() => {}
+    }
+    if !quiet {
+        println!("Shutting down, waiting for active downloads...");
+    }
+}
+
+/// Returns a token cancelled on SIGTERM, which container runtimes and
+/// service managers send to stop a process. The handler is installed right
+/// away, so a SIGTERM arriving as soon as the URL is printed still drains
+/// downloads instead of killing the process. Windows has no SIGTERM, so
+/// there the token is never cancelled.
+fn listen_for_sigterm() -> CancellationToken {
+    let terminated = CancellationToken::new();
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                let terminated = terminated.clone();
+                tokio::spawn(async move {
+                    sigterm.recv().await;
+                    terminated.cancel();
+                });
+            }
+            Err(e) => warn!(error = %e, "Could not listen for SIGTERM"),
+        }
+    }
+    terminated
+}
+
+// ==========================
+// Route Handlers
+// ==========================
+async fn register_selection(
+    State(state): State<AppState>,
+    Json(payload): Json<SelectionRequest>,
+) -> impl IntoResponse {
+    let SelectionRequest { files, dirs } = payload;
+
+    // Expand directories server-side
+    let mut all_files = files;
+    if !state.basename_only {
+        all_files.extend(expand_dirs(&state, dirs).await);
+    }
+
+    all_files.sort();
+    all_files.dedup();
+
+    let id = Uuid::new_v4().to_string();
+    let mut cache = state.download_cache.lock().unwrap();
+    cache.put(id.clone(), all_files);
+
+    Json(SelectionResponse { id })
+}
+
+/// Live download counters. Reading them is not a download, so it never
+/// counts towards `--max-downloads`.
+async fn stats_handler(State(state): State<AppState>) -> Json<StatsResponse> {
+    Json(StatsResponse {
+        completed_downloads: state.completed_downloads.load(Ordering::SeqCst),
+        bytes_served: state.bytes_served.load(Ordering::Relaxed),
+        active_downloads: state.active_downloads.load(Ordering::Relaxed),
+        uptime_secs: state.started.elapsed().as_secs(),
+    })
+}
+
+/// `--status-interval`: prints the `/stats` figures every `period` until `stop`.
+async fn print_status(state: AppState, period: Duration, stop: CancellationToken) {
+    // The first tick of a plain interval fires at once, when there is nothing to report
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            _ = stop.cancelled() => return,
+        }
+        println!(
+            "Status: up {}, {} downloads completed, {} active, {} served",
+            format_uptime(state.started.elapsed()),
+            state.completed_downloads.load(Ordering::SeqCst),
+            state.active_downloads.load(Ordering::Relaxed),
+            format_size(state.bytes_served.load(Ordering::Relaxed))
+        );
+    }
+}
+
+/// Formats an uptime to the second, e.g. `1h 02m 05s` or `45s`.
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let (hours, minutes, seconds) = (secs / 3_600, secs % 3_600 / 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+/// Liveness probe for monitors; it never touches the shared files.
+async fn health_handler(State(state): State<AppState>) -> Json<HealthResponse> {
+    Json(HealthResponse {
+        status: "ok",
+        uptime_secs: state.started.elapsed().as_secs(),
+    })
+}
+
+async fn config_handler(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let files = {
+        let mut cache = state.download_cache.lock().unwrap();
+        cache.get(&id).cloned()
+    };
+
+    if let Some(file_list) = files {
+        let mut config = format!(
+            "globoff\nparallel\nparallel-max = {}\nparallel-immediate\nprogress-meter\n",
+            state.parallel_downloads
+        );
+        // Resuming needs ranges; curl gives up rather than restart a whole file
+        if !state.no_ranges {
+            config.push_str("continue-at = -\n");
+        }
+
+        if state.self_signed {
+            config.push_str("insecure\n");
+        }
+
+        // This config is only reachable with valid credentials, so pass the
+        // caller's own on to the downloads; a password file may hold only hashes
+        if !state.users.is_empty()
+            && let Some(credentials) = basic_credentials(&headers)
+        {
+            config.push_str(&format!(
+                "user = \"{}\"\n",
+                escape_curl_config_value(&credentials)
+            ));
+        }
+
+        for path in file_list {
+            let escaped = escape_curl_config_value(&path);
+            config.push_str(&format!(
+                "url = \"{}/files/{}\"\noutput = \"{}\"\n\n",
+                state.host, escaped, escaped
+            ));
+        }
+
+        (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain")],
+            config,
+        )
+            .into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+async fn root_handler(State(state): State<AppState>, headers: HeaderMap) -> impl IntoResponse {
+    match &state.root {
+        Root::Path(root) if !root.is_dir() => render_landing_page(&state, root, &headers)
+            .await
+            .into_response(),
+        Root::Path(root) => {
+            render_directory(
+                root,
+                "",
+                &state.path_prefix,
+                !state.basename_only,
+                state.serve_symlinks,
+            )
+            .await
+        }
+        Root::Multi(entries) => render_entries(entries, &state.path_prefix, !state.basename_only)
+            .await
+            .into_response(),
+        Root::Manifest(files) => Html(generate_page_html_template(
+            None,
+            manifest_items(files, &state.path_prefix).await,
+            &state.path_prefix,
+        ))
+        .into_response(),
+        Root::Remote(remote) => match remote.fetch().await {
+            Ok(cached) => render_landing_page(&state, &cached.path, &headers)
+                .await
+                .into_response(),
+            Err(status) => status.into_response(),
+        },
+    }
+}
+
+/// Streams the served file when a single file (or `--proxy` URL) was given.
+async fn download_handler(
+    State(state): State<AppState>,
+    method: Method,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+) -> impl IntoResponse {
+    let name = state.download_name.as_deref();
+    match &state.root {
+        // Not `is_file`: a file deleted mid-run should be reported, not silently 404
+        Root::Path(root) if !root.is_dir() => {
+            serve_file(&state, root, name, None, &method, headers, addr).await
+        }
+        Root::Remote(remote) => match remote.fetch().await {
+            Ok(cached) => {
+                let mime = cached.content_type.as_deref();
+                serve_file(&state, &cached.path, name, mime, &method, headers, addr).await
+            }
+            Err(status) => status.into_response(),
+        },
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Streams the manifest entry mounted at the requested route.
+async fn manifest_handler(
+    State(state): State<AppState>,
+    method: Method,
+    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+) -> Response {
+    let route = uri.path().strip_prefix(state.path_prefix.as_str());
+    let Some(file) = route.and_then(|route| state.root.manifest_file(route)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let name = file.name.as_deref();
+    serve_file(&state, &file.path, name, None, &method, headers, addr)
+        .await
+        .into_response()
+}
+
+/// Catches near-misses of the download route (`/download` unless `--route`
+/// moved it): any letter case and a trailing slash are accepted, so
+/// `/Download`, `/DOWNLOAD/` and `/download/` all redirect (308, keeping the
+/// method) to the canonical route. Other paths are a 404.
+async fn fallback_handler(
+    State(state): State<AppState>,
+    OriginalUri(uri): OriginalUri,
+) -> Response {
+    // The token itself stays case-sensitive, it is a secret
+    let misspelled = uri
+        .path()
+        .strip_prefix(state.path_prefix.as_str())
+        .is_some_and(|rest| {
+            rest.trim_end_matches('/')
+                .eq_ignore_ascii_case(&state.download_route)
+        });
+    if !misspelled {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let query = uri.query().map(|q| format!("?{}", q)).unwrap_or_default();
+    Redirect::permanent(&format!(
+        "{}{}{}",
+        state.path_prefix, state.download_route, query
+    ))
+    .into_response()
+}
+
+async fn file_handler(
+    State(state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+    method: Method,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+) -> impl IntoResponse {
+    let full_path = match state.locate(&path) {
+        Ok(p) => p,
+        Err(status) => return status.into_response(),
+    };
+
+    if full_path.is_dir() {
+        render_directory(
+            &full_path,
+            &path,
+            &state.path_prefix,
+            !state.basename_only,
+            state.serve_symlinks,
+        )
+        .await
+    } else {
+        serve_file(&state, &full_path, None, None, &method, headers, addr).await
+    }
+}
+
+async fn root_checksum_handler(State(state): State<AppState>) -> Response {
+    checksum_response(&state, "").await
+}
+
+async fn checksum_handler(
+    State(state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+) -> Response {
+    checksum_response(&state, &path).await
+}
+
+async fn checksum_response(state: &AppState, path: &str) -> Response {
+    let full_path = match state.locate(path) {
+        Ok(p) if p.is_file() => p,
+        Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+        Err(status) => return status.into_response(),
+    };
+
+    if is_hashing(state, &full_path) {
+        return checksum_pending();
+    }
+
+    match file_checksum(state, &full_path).await {
+        Ok(digest) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain")],
+            format!("{}\n", to_hex(&digest)),
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn root_list_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    list_response(&state, "", query.sort).await
+}
+
+async fn list_handler(
+    State(state): State<AppState>,
+    AxumPath(path): AxumPath<String>,
+    Query(query): Query<ListQuery>,
+) -> Response {
+    list_response(&state, &path, query.sort).await
+}
+
+/// The HTML listing as JSON, for scripts. Only folders have listings, so a
+/// single-file share answers 404.
+async fn list_response(state: &AppState, path: &str, sort: ListSort) -> Response {
+    let show_dirs = !state.basename_only;
+    let mut items = match &state.root {
+        Root::Multi(entries) if path.trim_matches('/').is_empty() => {
+            entry_items(entries, &state.path_prefix, show_dirs).await
+        }
+        Root::Manifest(files) if path.trim_matches('/').is_empty() => {
+            manifest_items(files, &state.path_prefix).await
+        }
+        Root::Remote(_) | Root::Manifest(_) => return StatusCode::NOT_FOUND.into_response(),
+        _ => {
+            let full_path = match state.locate(path) {
+                Ok(p) if p.is_dir() => p,
+                Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+                Err(status) => return status.into_response(),
+            };
+            let base = path.trim_matches('/');
+            let items = directory_items(
+                &full_path,
+                base,
+                &state.path_prefix,
+                show_dirs,
+                state.serve_symlinks,
+            );
+            match items.await {
+                Ok(items) => items,
+                Err(e) => return directory_error(&full_path, e),
+            }
+        }
+    };
+
+    // Stable, so entries of equal size stay in name order
+    if let ListSort::Size = sort {
+        items.sort_by_key(|item| item.size.unwrap_or(0));
+    }
+    Json(items.iter().map(DirItem::to_entry).collect::<Vec<_>>()).into_response()
+}
+
+/// Every file under a folder share with its size and SHA-256, so a client can
+/// fetch only what differs from its own copy. Hashes come from the checksum
+/// cache and are computed on first request; `--watch` keeps them current.
+async fn sync_manifest_handler(State(state): State<AppState>) -> Response {
+    let Some(roots) = state.root.folder_roots() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut items = match tokio::task::spawn_blocking(move || zip_listing(&roots)).await {
+        Ok(items) => items,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    // Like `/download.zip`, `--basename-only` keeps the top level alone
+    items.retain(|item| !item.is_dir);
+    if state.basename_only {
+        items.retain(|item| !item.name.contains('/'));
+    }
+
+    // Hashing happens in the background; until every file is cached the client
+    // is told to retry, as `/checksum` does
+    let mut entries = Vec::with_capacity(items.len());
+    let mut pending = false;
+    for item in items {
+        // Files can vanish between the walk and the hash; they are simply left out.
+        // Canonical paths share cache entries with `/checksum`.
+        let Ok(path) = fs::canonicalize(&item.path).await else {
+            continue;
+        };
+        let Ok(metadata) = fs::metadata(&path).await else {
+            continue;
+        };
+        let sha256 = if metadata.len() > state.hash_threshold {
+            None
+        } else {
+            let digest = state.checksums.lock().unwrap().get(&path).copied();
+            if digest.is_none() {
+                spawn_checksum(&state, &path);
+                pending = true;
+            }
+            digest.map(|d| to_hex(&d))
+        };
+        entries.push(SyncEntry {
+            url: format!(
+                "{}/files/{}",
+                state.path_prefix,
+                encode_url_path(&item.name)
+            ),
+            path: item.name,
+            size: metadata.len(),
+            sha256,
+        });
+    }
+    if pending {
+        return checksum_pending();
+    }
+    Json(entries).into_response()
+}
+
+async fn root_info_handler(State(state): State<AppState>) -> Response {
+    info_response(&state, "").await
+}
+
+async fn info_handler(State(state): State<AppState>, AxumPath(path): AxumPath<String>) -> Response {
+    info_response(&state, &path).await
+}
+
+/// Describes a file with the same name, type and validators a download of it would carry.
+/// The SHA-256 is included once it has been computed; under `--checksum` a
+/// request starts hashing a file up to `--hash-threshold` in the background.
+async fn info_response(state: &AppState, path: &str) -> Response {
+    let full_path = match state.locate(path) {
+        Ok(p) if p.is_file() => p,
+        Ok(_) => return StatusCode::NOT_FOUND.into_response(),
+        Err(status) => return status.into_response(),
+    };
+    let metadata = match fs::metadata(&full_path).await {
+        Ok(m) => m,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let name = match &state.download_name {
+        Some(name) if path.is_empty() => name.clone(),
+        _ => file_display_name(&full_path, "download"),
+    };
+    let modified = metadata.modified().ok();
+    // Never hold the answer back for hashing, which can take minutes; like the
+    // Digest header, the hash shows up once it is known
+    let sha256 = state.checksums.lock().unwrap().get(&full_path).copied();
+    if state.checksum && sha256.is_none() && metadata.len() <= state.hash_threshold {
+        spawn_checksum(state, &full_path);
+    }
+
+    Json(FileInfo {
+        name,
+        size: metadata.len(),
+        mime: state
+            .content_type
+            .clone()
+            .unwrap_or_else(|| mime_guess::from_path(&full_path).first_or_octet_stream())
+            .to_string(),
+        etag: entity_tag(metadata.len(), modified),
+        modified: modified.map(httpdate::fmt_http_date),
+        sha256: sha256.map(|digest| to_hex(&digest)),
+    })
+    .into_response()
+}
+
+// ==========================
+// Request Logging
+// ==========================
+async fn log_request(
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    // Streamed bodies are still in flight here, so report the advertised length
+    let bytes = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+
+    info!(
+        client_ip = %addr,
+        method = %method,
+        path = %path,
+        status = response.status().as_u16(),
+        bytes = %bytes,
+        duration_ms = start.elapsed().as_millis() as u64,
+        "Request"
+    );
+
+    response
+}
+
+// ==========================
+// Authentication
+// ==========================
+async fn require_auth(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    // A manifest entry with its own credentials accepts only those
+    let path = request.uri().path();
+    let users = path
+        .strip_prefix(state.path_prefix.as_str())
+        .and_then(|route| state.root.manifest_file(route))
+        .map(|file| file.users.clone())
+        .filter(|users| !users.is_empty())
+        .unwrap_or_else(|| state.users.clone());
+    // Monitors should not need the share's credentials, and /health exposes nothing
+    if users.is_empty() || path == "/health" {
+        return next.run(request).await;
+    }
+
+    match basic_credentials(request.headers()) {
+        Some(supplied) if check_credentials(&state, &users, &supplied).await => {
+            next.run(request).await
+        }
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Basic realm=\"wifi_file_server\"")],
+        )
+            .into_response(),
+    }
+}
+
+// ==========================
+// Security Headers
+// ==========================
+/// What the built-in pages need, their inline styles and scripts plus
+/// requests back to the share, and nothing from anywhere else.
+const PAGE_CSP: &str = "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; \
+    img-src 'self' data:; connect-src 'self'; form-action 'self'; base-uri 'none'; \
+    frame-ancestors 'none'";
+
+/// Served files that a browser would run script from when previewed inline.
+const ACTIVE_TYPES: &[&str] = &["text/html", "application/xhtml+xml", "image/svg+xml"];
+
+/// Stops browsers sniffing types and framing the share, and gives HTML pages
+/// `PAGE_CSP` unless `serve_file` already sandboxed them.
+async fn security_headers(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+    let html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|t| t.starts_with("text/html"));
+    if html && !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static(PAGE_CSP),
+        );
+    }
+    response
+}
+
+// ==========================
+// Client Allow-List
+// ==========================
+/// Turns away clients outside `--allow` before anything else looks at the
+/// request.
+async fn allow_clients(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Unix socket peers have no address; the proxy in front decides who gets in
+    let ClientAddr::Tcp(addr) = addr else {
+        return next.run(request).await;
+    };
+    // A dual-stack listener sees IPv4 clients as ::ffff:a.b.c.d
+    let ip = addr.ip().to_canonical();
+    if state.allow.is_empty() || state.allow.iter().any(|net| net.contains(&ip)) {
+        return next.run(request).await;
+    }
+
+    warn!(client = %ip, "Client not in --allow, refusing");
+    StatusCode::FORBIDDEN.into_response()
+}
+
+// ==========================
+// Request Throttling
+// ==========================
+/// Fixed one-minute request windows per client IP for `--rate-limit`.
+struct Throttle {
+    limit: u32,
+    windows: Mutex<ThrottleWindows>,
+}
+
+struct ThrottleWindows {
+    /// When each client's current window opened and how many requests it has made
+    clients: HashMap<IpAddr, (Instant, u32)>,
+    last_pruned: Instant,
+}
+
+const THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+
+impl Throttle {
+    fn new(limit: u32) -> Self {
+        Throttle {
+            limit,
+            windows: Mutex::new(ThrottleWindows {
+                clients: HashMap::new(),
+                last_pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts a request from `ip`. Returns how long until the client may try
+    /// again when it is over the limit.
+    fn check(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        // Clients that went quiet would otherwise stay in the map forever
+        if now.duration_since(windows.last_pruned) >= THROTTLE_WINDOW {
+            windows
+                .clients
+                .retain(|_, (opened, _)| now.duration_since(*opened) < THROTTLE_WINDOW);
+            windows.last_pruned = now;
+        }
+
+        let (opened, count) = windows.clients.entry(ip).or_insert((now, 0));
+        if now.duration_since(*opened) >= THROTTLE_WINDOW {
+            *opened = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return Some(THROTTLE_WINDOW - now.duration_since(*opened));
+        }
+        *count += 1;
+        None
+    }
+}
+
+/// Answers 429 once a client exceeds `--rate-limit`. Only new requests are
+/// refused; bodies already streaming are never cut off.
+async fn throttle_requests(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Unix socket peers all look alike, so leave limiting to the proxy in front
+    let (Some(throttle), ClientAddr::Tcp(addr)) = (&state.throttle, addr) else {
+        return next.run(request).await;
+    };
+
+    match throttle.check(addr.ip()) {
+        None => next.run(request).await,
+        Some(wait) => {
+            warn!(client = %addr.ip(), "Request rate limit exceeded");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, (wait.as_secs() + 1).to_string())],
+                "Too many requests, slow down\n",
+            )
+                .into_response()
+        }
+    }
+}
+
+/// The decoded `user:pass` of a Basic `Authorization` header.
+fn basic_credentials(headers: &HeaderMap) -> Option<String> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()
+}
+
+/// Checks `user:pass` against `users`. Bcrypt runs on the blocking pool,
+/// and pairs that passed it are remembered.
+async fn check_credentials(state: &AppState, users: &[Credentials], supplied: &str) -> bool {
+    let Some((user, pass)) = supplied.split_once(':') else {
+        return false;
+    };
+    let Some(entry) = users.iter().find(|c| c.user == user) else {
+        return false;
+    };
+    if !is_bcrypt_hash(&entry.pass) {
+        return constant_time_eq(pass.as_bytes(), entry.pass.as_bytes());
+    }
+
+    // Keyed by the hash too, as manifest entries may give one user different passwords
+    let digest: [u8; 32] = Sha256::new()
+        .chain_update(entry.pass.as_bytes())
+        .chain_update(supplied.as_bytes())
+        .finalize()
+        .into();
+    if state.verified.lock().unwrap().contains(&digest) {
+        return true;
+    }
+    let (pass, hash) = (pass.to_string(), entry.pass.clone());
+    let valid = tokio::task::spawn_blocking(move || bcrypt::verify(pass, &hash).unwrap_or(false))
+        .await
+        .unwrap_or(false);
+    if valid {
+        state.verified.lock().unwrap().insert(digest);
+    }
+    valid
+}
+
+fn is_bcrypt_hash(pass: &str) -> bool {
+    ["$2a$", "$2b$", "$2y$"].iter().any(|p| pass.starts_with(p))
+}
+
+/// Reads `user:password` lines for `--password-file`, skipping blank lines
+/// and `#` comments.
+fn load_password_file(path: &Path) -> io::Result<Vec<Credentials>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| context(e, format!("Could not read {}", path.display())))?;
+    let mut users = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, pass)) if !user.is_empty() => users.push(Credentials {
+                user: user.to_string(),
+                pass: pass.to_string(),
+            }),
+            _ => {
+                return Err(io::Error::other(format!(
+                    "{} line {}: expected user:password",
+                    path.display(),
+                    number + 1
+                )));
+            }
+        }
+    }
+    if users.is_empty() {
+        return Err(io::Error::other(format!(
+            "{} contains no credentials",
+            path.display()
+        )));
+    }
+    Ok(users)
+}
+
+/// Compares without short-circuiting so response timing doesn't reveal how
+/// much of the secret matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// ==========================
+// Uploads
+// ==========================
+/// A file-picker form for people uploading from a browser.
+async fn upload_page(State(state): State<AppState>) -> Html<String> {
+    render_upload_page(&state, "")
+}
+
+/// Saves every file field of a multipart POST. Scripts get the saved names
+/// as JSON; a browser posting the upload form without JavaScript gets the
+/// form back with a success message.
+async fn upload_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    let Some(dir) = &state.upload_dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut saved = Vec::new();
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return (e.status(), e.body_text()).into_response(),
+        };
+        // Plain form fields carry no file name and are not uploads
+        let Some(name) = field.file_name().map(upload_file_name) else {
+            continue;
+        };
+
+        let refused = || {
+            warn!(client = %addr, file = %name, "Upload refused, file already exists");
+            (StatusCode::CONFLICT, format!("{} already exists\n", name)).into_response()
+        };
+        // Checked again on completion, but refusing now saves receiving the data at all
+        if state.upload_conflict == UploadConflict::Refuse && dir.join(&name).exists() {
+            return refused();
+        }
+        let (file, part) = match create_part_file(dir).await {
+            Ok(created) => created,
+            Err(e) => {
+                error!(error = %e, "Could not create upload file");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        // The data only takes the final name once all of it has arrived
+        let size = match save_field(&mut field, file).await {
+            Ok(size) => size,
+            Err(response) => {
+                let _ = fs::remove_file(&part).await;
+                return response;
+            }
+        };
+        let target = match place_upload(&part, dir, &name, state.upload_conflict).await {
+            Ok(target) => target,
+            Err(e) => {
+                let _ = fs::remove_file(&part).await;
+                if e.kind() == io::ErrorKind::AlreadyExists {
+                    return refused();
+                }
+                error!(error = %e, file = %part.display(), "Could not save finished upload");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+
+        info!(
+            client = %addr,
+            file = %target.display(),
+            requested = %name,
+            size,
+            "File upload"
+        );
+        saved.push(file_display_name(&target, &name));
+    }
+
+    let browser = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    if browser {
+        let message = format!("Uploaded: {}", saved.join(", "));
+        return render_upload_page(&state, &escape_html(&message)).into_response();
+    }
+    Json(UploadResponse { files: saved }).into_response()
+}
+
+fn render_upload_page(state: &AppState, message: &str) -> Html<String> {
+    let html_template = include_str!("../upload.html");
+    Html(
+        html_template
+            .replace("{{UPLOAD}}", &format!("{}/upload", state.path_prefix))
+            .replace("{{PREFIX}}", &state.path_prefix)
+            .replace("{{MESSAGE}}", message),
+    )
+}
+
+async fn save_field(field: &mut Field<'_>, mut file: File) -> Result<u64, Response> {
+    let internal_error = |_| StatusCode::INTERNAL_SERVER_ERROR.into_response();
+
+    let mut size: u64 = 0;
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| (e.status(), e.body_text()).into_response())?
+    {
+        file.write_all(&chunk).await.map_err(internal_error)?;
+        size += chunk.len() as u64;
+    }
+    file.flush().await.map_err(internal_error)?;
+
+    Ok(size)
+}
+
+/// Creates a uniquely named `.part` file in the partial folder for a
+/// multipart upload to be written into, so concurrent uploads never share one.
+async fn create_part_file(dir: &Path) -> io::Result<(File, PathBuf)> {
+    let partial = partial_dir(dir);
+    fs::create_dir_all(&partial).await?;
+    let part = partial.join(format!("{}.part", Uuid::new_v4()));
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&part)
+        .await?;
+    Ok((file, part))
+}
+
+/// Moves the complete upload at `staged` to its final name `name` in `dir`,
+/// following `policy` when the name is taken. Both multipart and resumable
+/// uploads finish here. Outside `Replace` the name is first claimed with
+/// `create_new` and only that empty claim is renamed over, so a file that
+/// appears meanwhile is never overwritten. A refused upload fails with
+/// `AlreadyExists` and leaves `staged` in place.
+async fn place_upload(
+    staged: &Path,
+    dir: &Path,
+    name: &str,
+    policy: UploadConflict,
+) -> io::Result<PathBuf> {
+    let target = dir.join(name);
+    if policy == UploadConflict::Replace {
+        fs::rename(staged, &target).await?;
+        return Ok(target);
+    }
+
+    let mut candidate = target;
+    for n in 1.. {
+        let claimed = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&candidate)
+            .await;
+        match claimed {
+            Ok(file) => {
+                drop(file);
+                if let Err(e) = fs::rename(staged, &candidate).await {
+                    let _ = fs::remove_file(&candidate).await;
+                    return Err(e);
+                }
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                if policy == UploadConflict::Refuse {
+                    return Err(e);
+                }
+                candidate = dir.join(numbered_name(name, n));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!()
+}
+
+/// `report.pdf` becomes `report-1.pdf` for `n` = 1.
+fn numbered_name(name: &str, n: u32) -> String {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map_or(name.into(), |s| s.to_string_lossy());
+    match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, n, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, n),
+    }
+}
+
+/// Cleans a client-supplied name with [`sanitize_filename`] so an upload can
+/// never be written outside the upload folder, or under a device name.
+fn upload_file_name(name: &str) -> String {
+    match sanitize_filename(name) {
+        name if name.is_empty() => "upload".to_string(),
+        name => name,
+    }
+}
+
+// ==========================
+// Resumable Uploads
+// ==========================
+// The tus 1.0.0 core protocol with its creation extension: POST /uploads
+// creates an upload, HEAD reports how far it got and PATCH appends from there.
+// Partial data is kept in the upload folder, so an upload survives a restart.
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+
+/// What a resumable upload will become, saved next to its partial data.
+#[derive(Serialize, Deserialize)]
+struct PartialUpload {
+    name: String,
+    length: u64,
+}
+
+/// Removes an upload from [`AppState::appending`] when its PATCH ends.
+struct AppendGuard {
+    appending: Arc<Mutex<HashSet<Uuid>>>,
+    id: Uuid,
+}
+
+impl Drop for AppendGuard {
+    fn drop(&mut self) {
+        self.appending.lock().unwrap().remove(&self.id);
+    }
+}
+
+async fn tus_options(State(state): State<AppState>) -> Response {
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    let headers = response.headers_mut();
+    headers.insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    headers.insert("tus-extension", HeaderValue::from_static("creation"));
+    if let Some(max) = state.max_upload_size {
+        headers.insert("tus-max-size", HeaderValue::from(max));
+    }
+    response
+}
+
+async fn create_upload(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(dir) = &state.upload_dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Some(response) = tus_version_mismatch(&headers) {
+        return response;
+    }
+    // Upload-Defer-Length is an extension we don't offer
+    let Some(length) = header_u64(&headers, &UPLOAD_LENGTH) else {
+        return tus_response(StatusCode::BAD_REQUEST);
+    };
+    if state.max_upload_size.is_some_and(|max| length > max) {
+        return tus_response(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    let name = upload_metadata(&headers, "filename")
+        .map(|name| upload_file_name(&name))
+        .unwrap_or_else(|| "upload".to_string());
+    // Checked again on completion, but refusing now saves sending the data at all
+    if state.upload_conflict == UploadConflict::Refuse && dir.join(&name).exists() {
+        return tus_response(StatusCode::CONFLICT);
+    }
+    let id = Uuid::new_v4();
+    let (data, info) = partial_paths(dir, id);
+    let upload = PartialUpload { name, length };
+    let created = async {
+        fs::create_dir_all(partial_dir(dir)).await?;
+        File::create(&data).await?;
+        fs::write(&info, serde_json::to_vec(&upload)?).await
+    };
+    if let Err(e) = created.await {
+        error!(error = %e, "Could not create resumable upload");
+        return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    info!(client = %addr, id = %id, file = %upload.name, length, "Resumable upload created");
+
+    if length == 0
+        && let Err(e) = finish_upload(&state, dir, id, &upload, addr).await
+    {
+        return finish_failed(e, &upload.name);
+    }
+
+    let mut response = tus_response(StatusCode::CREATED);
+    let location = format!("{}/uploads/{}", state.path_prefix, id);
+    if let Some(location) = header_value(&location) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+async fn upload_offset(
+    State(state): State<AppState>,
+    AxumPath(id): AxumPath<String>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = tus_version_mismatch(&headers) {
+        return response;
+    }
+    let Some((upload, offset)) = load_upload(&state, &id).await else {
+        return tus_response(StatusCode::NOT_FOUND);
+    };
+
+    let mut response = tus_response(StatusCode::OK);
+    let headers = response.headers_mut();
+    headers.insert(UPLOAD_OFFSET, HeaderValue::from(offset));
+    headers.insert(UPLOAD_LENGTH, HeaderValue::from(upload.length));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+async fn append_upload(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+    AxumPath(id): AxumPath<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let Some(dir) = &state.upload_dir else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    if let Some(response) = tus_version_mismatch(&headers) {
+        return response;
+    }
+    let content_type = headers.get(header::CONTENT_TYPE);
+    if content_type.is_none_or(|t| t != "application/offset+octet-stream") {
+        return tus_response(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    let Some(client_offset) = header_u64(&headers, &UPLOAD_OFFSET) else {
+        return tus_response(StatusCode::BAD_REQUEST);
+    };
+    let Ok(uuid) = Uuid::parse_str(&id) else {
+        return tus_response(StatusCode::NOT_FOUND);
+    };
+    if !state.appending.lock().unwrap().insert(uuid) {
+        return tus_response(StatusCode::CONFLICT);
+    }
+    let _guard = AppendGuard {
+        appending: state.appending.clone(),
+        id: uuid,
+    };
+
+    let Some((upload, offset)) = load_upload(&state, &id).await else {
+        return tus_response(StatusCode::NOT_FOUND);
+    };
+    if client_offset != offset {
+        return tus_response(StatusCode::CONFLICT);
+    }
+
+    let (data, _) = partial_paths(dir, uuid);
+    let mut file = match fs::OpenOptions::new().append(true).open(&data).await {
+        Ok(file) => file,
+        Err(e) => {
+            error!(error = %e, "Could not open resumable upload");
+            return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // Whatever arrives before a dropped connection is kept for the next PATCH
+    let mut offset = offset;
+    let mut too_long = false;
+    let mut chunks = body.into_data_stream();
+    while let Some(Ok(chunk)) = chunks.next().await {
+        let room = upload.length.saturating_sub(offset);
+        let take = chunk.len().min(usize::try_from(room).unwrap_or(usize::MAX));
+        if let Err(e) = file.write_all(&chunk[..take]).await {
+            error!(error = %e, "Could not write resumable upload");
+            return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        offset += take as u64;
+        if take < chunk.len() {
+            too_long = true;
+            break;
+        }
+    }
+    if let Err(e) = file.flush().await {
+        error!(error = %e, "Could not write resumable upload");
+        return tus_response(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    drop(file);
+
+    if offset == upload.length
+        && let Err(e) = finish_upload(&state, dir, uuid, &upload, addr).await
+    {
+        return finish_failed(e, &upload.name);
+    }
+    if too_long {
+        return tus_response(StatusCode::BAD_REQUEST);
+    }
+
+    let mut response = tus_response(StatusCode::NO_CONTENT);
+    response
+        .headers_mut()
+        .insert(UPLOAD_OFFSET, HeaderValue::from(offset));
+    response
+}
+
+/// Reads an upload's target and how many bytes of it have arrived, or None
+/// for an unknown or already finished upload.
+async fn load_upload(state: &AppState, id: &str) -> Option<(PartialUpload, u64)> {
+    let dir = state.upload_dir.as_ref()?;
+    let (data, info) = partial_paths(dir, Uuid::parse_str(id).ok()?);
+    let upload = serde_json::from_slice(&fs::read(&info).await.ok()?).ok()?;
+    let offset = fs::metadata(&data).await.ok()?.len();
+    Some((upload, offset))
+}
+
+/// Moves a complete upload out of the partial folder under its final name
+/// with [`place_upload`]. A refused name can never succeed on a retry, so the
+/// upload is discarded then; after any other failure it stays, and a PATCH at
+/// the final offset tries again.
+async fn finish_upload(
+    state: &AppState,
+    dir: &Path,
+    id: Uuid,
+    upload: &PartialUpload,
+    addr: ClientAddr,
+) -> io::Result<()> {
+    let (data, info) = partial_paths(dir, id);
+    let target = match place_upload(&data, dir, &upload.name, state.upload_conflict).await {
+        Ok(target) => target,
+        Err(e) => {
+            if e.kind() == io::ErrorKind::AlreadyExists {
+                let _ = fs::remove_file(&data).await;
+                let _ = fs::remove_file(&info).await;
+            }
+            return Err(e);
+        }
+    };
+    let _ = fs::remove_file(&info).await;
+    info!(
+        client = %addr,
+        file = %target.display(),
+        requested = %upload.name,
+        size = upload.length,
+        "File upload"
+    );
+    Ok(())
+}
+
+/// Answers a failed [`finish_upload`], with 409 for a refused name.
+fn finish_failed(e: io::Error, name: &str) -> Response {
+    if e.kind() == io::ErrorKind::AlreadyExists {
+        warn!(file = %name, "Upload refused, file already exists");
+        return tus_response(StatusCode::CONFLICT);
+    }
+    error!(error = %e, "Could not save resumable upload");
+    tus_response(StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Where in-progress uploads of both kinds live inside the upload folder.
+/// It is left out of listings and archives and cannot be downloaded from.
+const PARTIAL_DIR: &str = ".wfpush-partial";
+
+fn partial_dir(dir: &Path) -> PathBuf {
+    dir.join(PARTIAL_DIR)
+}
+
+/// The data and info files of one resumable upload.
+fn partial_paths(dir: &Path, id: Uuid) -> (PathBuf, PathBuf) {
+    let partial = partial_dir(dir);
+    (
+        partial.join(id.to_string()),
+        partial.join(format!("{}.json", id)),
+    )
+}
+
+/// Answers clients that speak a tus version other than ours with 412.
+fn tus_version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    if headers
+        .get(&TUS_RESUMABLE)
+        .is_some_and(|v| v == TUS_VERSION)
+    {
+        return None;
+    }
+    let mut response = tus_response(StatusCode::PRECONDITION_FAILED);
+    response
+        .headers_mut()
+        .insert("tus-version", HeaderValue::from_static(TUS_VERSION));
+    Some(response)
+}
+
+/// An empty response carrying the `Tus-Resumable` header every tus reply needs.
+fn tus_response(status: StatusCode) -> Response {
+    (status, [(TUS_RESUMABLE, TUS_VERSION)]).into_response()
+}
+
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Decodes one key of an `Upload-Metadata` header, a comma separated list
+/// of `key base64value` pairs.
+fn upload_metadata(headers: &HeaderMap, key: &str) -> Option<String> {
+    let metadata = headers.get("upload-metadata")?.to_str().ok()?;
+    let value = metadata.split(',').find_map(|pair| {
+        let (k, v) = pair.trim().split_once(' ')?;
+        (k == key).then_some(v)
+    })?;
+    String::from_utf8(BASE64.decode(value.trim()).ok()?).ok()
+}
+
+// ==========================
+// TLS
+// ==========================
+struct TlsSetup {
+    config: RustlsConfig,
+    fingerprint: String,
+    self_signed: bool,
+}
+
+/// Loads the given PEM pair, or issues a self-signed certificate for `hosts`
+/// (and localhost) when none was supplied. With `client_ca`, clients must
+/// present a certificate that CA signed.
+async fn load_tls(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    mut hosts: Vec<String>,
+    client_ca: Option<&Path>,
+) -> io::Result<TlsSetup> {
+    let (cert_pem, key_pem, self_signed) = match (cert, key) {
+        (Some(cert), Some(key)) => (fs::read(cert).await?, fs::read(key).await?, false),
+        _ => {
+            hosts.push("localhost".to_string());
+            let generated = rcgen::generate_simple_self_signed(hosts).map_err(io::Error::other)?;
+            (
+                generated.cert.pem().into_bytes(),
+                generated.signing_key.serialize_pem().into_bytes(),
+                true,
+            )
+        }
+    };
+
+    let leaf = CertificateDer::pem_slice_iter(&cert_pem)
+        .next()
+        .ok_or_else(|| io::Error::other("no certificate found"))?
+        .map_err(io::Error::other)?;
+    let fingerprint = Sha256::digest(&leaf)
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":");
+
+    // Offers h2 and http/1.1 over ALPN, so download managers can multiplex ranges
+    let config = match client_ca {
+        None => RustlsConfig::from_pem(cert_pem, key_pem).await?,
+        Some(ca) => {
+            let verifier = client_verifier(ca).await?;
+            let chain = CertificateDer::pem_slice_iter(&cert_pem)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(io::Error::other)?;
+            let key = PrivateKeyDer::from_pem_slice(&key_pem).map_err(io::Error::other)?;
+            let mut config = rustls::ServerConfig::builder()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(chain, key)
+                .map_err(io::Error::other)?;
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            RustlsConfig::from_config(Arc::new(config))
+        }
+    };
+
+    Ok(TlsSetup {
+        config,
+        fingerprint,
+        self_signed,
+    })
+}
+
+/// Checks client certificates against the CAs in the `--client-ca` PEM file.
+/// A client without a valid certificate fails the handshake.
+async fn client_verifier(path: &Path) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let pem = fs::read(path)
+        .await
+        .map_err(|e| context(e, format!("Could not read {}", path.display())))?;
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        roots
+            .add(cert.map_err(io::Error::other)?)
+            .map_err(|e| context(e, format!("Could not use {}", path.display())))?;
+    }
+    if roots.is_empty() {
+        return Err(io::Error::other(format!(
+            "{} contains no certificates",
+            path.display()
+        )));
+    }
+    WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(io::Error::other)
+}
+
+/// The TLS handshake plus a log line naming the subject of the client's
+/// certificate, when it sent one, so `--client-ca` shares show who connected.
+#[derive(Clone)]
+struct ClientCertLogger(RustlsAcceptor);
+
+impl<S: Send + 'static> Accept<TcpStream, S> for ClientCertLogger {
+    type Stream = <RustlsAcceptor as Accept<TcpStream, S>>::Stream;
+    type Service = S;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, S)>> + Send>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let peer = stream.peer_addr().ok();
+        let handshake = self.0.accept(stream, service);
+        Box::pin(async move {
+            let (stream, service) = handshake.await?;
+            if let Some(cert) = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|c| c.first())
+            {
+                let subject = x509_parser::parse_x509_certificate(cert)
+                    .map(|(_, cert)| cert.subject().to_string())
+                    .unwrap_or_else(|_| "unreadable".to_string());
+                match peer {
+                    Some(peer) => {
+                        info!(client = %peer.ip(), subject = %subject, "Client certificate accepted")
+                    }
+                    None => info!(subject = %subject, "Client certificate accepted"),
+                }
+            }
+            Ok((stream, service))
+        })
+    }
+}
+
+/// Picks the first address of the requested family on the named interface.
+fn interface_ip(name: &str, ipv6: bool) -> Option<IpAddr> {
+    local_ip_address::list_afinet_netifas()
+        .ok()?
+        .into_iter()
+        .find(|(iface, ip)| iface == name && ip.is_ipv6() == ipv6)
+        .map(|(_, ip)| ip)
+}
+
+// ==========================
+// mDNS
+// ==========================
+struct MdnsAdvert {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvert {
+    /// Withdraws the record so peers drop it right away instead of waiting for the TTL.
+    fn stop(self) {
+        if let Ok(done) = self.daemon.unregister(&self.fullname) {
+            let _ = done.recv_timeout(Duration::from_secs(1));
+        }
+        if let Ok(done) = self.daemon.shutdown() {
+            let _ = done.recv_timeout(Duration::from_secs(1));
+        }
+    }
+}
+
+/// Publishes the share as an `_http._tcp` service on `<hostname>.local`.
+fn advertise_mdns(hostname: &str, ip: IpAddr, port: u16) -> Result<MdnsAdvert, mdns_sd::Error> {
+    let daemon = ServiceDaemon::new()?;
+    let service = ServiceInfo::new(
+        "_http._tcp.local.",
+        hostname,
+        &format!("{}.local.", hostname),
+        ip,
+        port,
+        &[("path", "/")][..],
+    )?;
+    let fullname = service.get_fullname().to_string();
+    daemon.register(service)?;
+    Ok(MdnsAdvert { daemon, fullname })
+}
+
+// ==========================
+// Utilities
+// ==========================
+fn sanitize_path(path: &str) -> PathBuf {
+    Path::new(path)
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect()
+}
+
+pub fn escape_curl_config_value(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            ',' => out.push_str("\\,"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn escape_html(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Percent-encodes each segment of a slash-separated path for use in a URL.
+fn encode_url_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                out.push(byte as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+/// Undoes the percent-encoding of one URL segment. Malformed escapes are kept
+/// as they are and invalid UTF-8 is replaced, since the result is only a name.
+fn decode_url_segment(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escape {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Parses a header value built at runtime, logging instead of panicking if
+/// it somehow holds characters HTTP does not allow.
+fn header_value(value: &str) -> Option<HeaderValue> {
+    HeaderValue::from_str(value)
+        .inspect_err(|e| error!(value = %value, error = %e, "Invalid header value"))
+        .ok()
+}
+
+/// A bare 500 for failures that were already logged; one bad request must
+/// never take the server down.
+fn internal_error() -> Response {
+    (StatusCode::INTERNAL_SERVER_ERROR, "Internal server error\n").into_response()
+}
+
+/// Names Windows reserves for devices, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Turns a name from a client, a manifest or the command line into one that
+/// is safe to save under on any OS. Only the last component after `/` or `\`
+/// is kept, control characters are dropped, characters Windows forbids become
+/// `_`, leading and trailing dots and spaces go, and a reserved device name
+/// such as `CON` or `nul.txt` gets a leading `_`. The result may be empty, so
+/// callers supply their own fallback. Sanitizing twice changes nothing.
+pub fn sanitize_filename(name: &str) -> String {
+    let last = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = last
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    let trimmed = cleaned.trim_matches(['.', ' ']);
+
+    let stem = trimmed.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES.iter().any(|r| r.eq_ignore_ascii_case(stem)) {
+        format!("_{}", trimmed)
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// The last component of `path` as shown to clients, run through
+/// [`sanitize_filename`]. Backslashes count as separators as well, so
+/// `C:\Users\me\report.pdf` yields `report.pdf` even where `Path` only splits
+/// on `/`; a bare drive such as `C:` gets `fallback`.
+fn file_display_name(path: &Path, fallback: &str) -> String {
+    path.file_name()
+        .and_then(|name| {
+            let name = name.to_string_lossy();
+            name.rsplit(['/', '\\'])
+                .next()
+                .filter(|n| !n.ends_with(':'))
+                .map(sanitize_filename)
+                .filter(|n| !n.is_empty())
+        })
+        .unwrap_or_else(|| fallback.to_string())
+}
+
+/// Builds a `Content-Disposition` value with a plain ASCII `filename` for old
+/// clients and an RFC 5987 `filename*` that carries the exact UTF-8 name.
+fn content_disposition(disposition: &str, filename: &str) -> HeaderValue {
+    let filename = match sanitize_filename(filename) {
+        name if name.is_empty() => "download".to_string(),
+        name => name,
+    };
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut encoded = String::with_capacity(filename.len());
+    for byte in filename.bytes() {
+        match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    let value = format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, fallback, encoded
+    );
+    // Only printable ASCII remains, so this cannot fail
+    HeaderValue::from_str(&value).unwrap()
+}
+
+fn print_qr(url: &str) {
+    match QrCode::new(url) {
+        // Inverted so the code reads correctly on dark terminal backgrounds
+        Ok(code) => println!(
+            "{}",
+            code.render::<unicode::Dense1x2>()
+                .dark_color(unicode::Dense1x2::Light)
+                .light_color(unicode::Dense1x2::Dark)
+                .build()
+        ),
+        Err(e) => eprintln!("Warning: Could not render QR code. Error: {}", e),
+    }
+}
+
+fn write_qr_png(url: &str, path: &Path) -> Result<(), String> {
+    let code = QrCode::new(url).map_err(|e| e.to_string())?;
+    code.render::<Luma<u8>>()
+        .min_dimensions(256, 256)
+        .quiet_zone(true)
+        .build()
+        .save(path)
+        .map_err(|e| e.to_string())
+}
+
+/// Formats a byte count with binary units, e.g. `1.5 MB` for 1536 KiB.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// ==========================
+// Directory Rendering
+// ==========================
+/// Lists `dir`, which lives at `base` below the share root. `prefix` is
+/// prepended to every link (see `AppState::path_prefix`); subfolders are
+/// only listed when `show_dirs` is set, symlinks only with `show_symlinks`.
+pub async fn render_directory(
+    dir: &Path,
+    base: &str,
+    prefix: &str,
+    show_dirs: bool,
+    show_symlinks: bool,
+) -> Response {
+    let items = match directory_items(dir, base, prefix, show_dirs, show_symlinks).await {
+        Ok(items) => items,
+        Err(e) => return directory_error(dir, e),
+    };
+
+    // Parent directory info
+    let parent = if !base.is_empty() {
+        Some(
+            Path::new(base)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .to_string_lossy()
+                .to_string(),
+        )
+    } else {
+        None
+    };
+
+    Html(generate_page_html_template(parent, items, prefix)).into_response()
+}
+
+/// Lists the paths given on the command line as if they shared one folder.
+async fn render_entries(
+    entries: &BTreeMap<String, PathBuf>,
+    prefix: &str,
+    show_dirs: bool,
+) -> impl IntoResponse {
+    let items = entry_items(entries, prefix, show_dirs).await;
+    Html(generate_page_html_template(None, items, prefix))
+}
+
+/// The entries of `dir`, whose URL path is `base`, sorted by name. Symlinks
+/// are described by what they point at, or left out without `show_symlinks`.
+async fn directory_items(
+    dir: &Path,
+    base: &str,
+    prefix: &str,
+    show_dirs: bool,
+    show_symlinks: bool,
+) -> io::Result<Vec<DirItem>> {
+    let mut entries = fs::read_dir(dir).await?;
+    let mut items: Vec<DirItem> = Vec::new();
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name == PARTIAL_DIR {
+            continue;
+        }
+        let is_symlink = entry.file_type().await.is_ok_and(|t| t.is_symlink());
+        if is_symlink && !show_symlinks {
+            debug!(path = %entry.path().display(), "Hiding symlink from the listing");
+            continue;
+        }
+        let metadata = if is_symlink {
+            fs::metadata(entry.path()).await.ok()
+        } else {
+            entry.metadata().await.ok()
+        };
+        if !show_dirs && metadata.as_ref().is_none_or(|m| m.is_dir()) {
+            continue;
+        }
+        items.push(DirItem::new(name, base, prefix, metadata.as_ref()));
+    }
+
+    items.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
+    Ok(items)
+}
+
+/// The paths given on the command line as entries of one folder, sorted by name.
+async fn entry_items(
+    entries: &BTreeMap<String, PathBuf>,
+    prefix: &str,
+    show_dirs: bool,
+) -> Vec<DirItem> {
+    let mut items: Vec<DirItem> = Vec::new();
+    for (name, path) in entries {
+        let metadata = fs::metadata(path).await.ok();
+        if !show_dirs && metadata.as_ref().is_none_or(|m| m.is_dir()) {
+            continue;
+        }
+        items.push(DirItem::new(name.clone(), "", prefix, metadata.as_ref()));
+    }
+
+    items.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
+    items
+}
+
+/// The files of a `--manifest`, linked at their own routes, sorted by name.
+async fn manifest_items(files: &[ManifestFile], prefix: &str) -> Vec<DirItem> {
+    let mut items: Vec<DirItem> = Vec::new();
+    for file in files {
+        let metadata = fs::metadata(&file.path).await.ok();
+        let name = file
+            .name
+            .clone()
+            .unwrap_or_else(|| file_display_name(&file.path, "download"));
+        let mut item = DirItem::new(name, "", prefix, metadata.as_ref());
+        item.path = file.route.trim_start_matches('/').to_string();
+        item.link_path = format!("{}{}", prefix, file.route);
+        items.push(item);
+    }
+
+    items.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
+    items
+}
+
+fn directory_error(dir: &Path, e: io::Error) -> Response {
+    match e.kind() {
+        io::ErrorKind::NotFound => StatusCode::NOT_FOUND.into_response(),
+        io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN.into_response(),
+        _ => {
+            error!(dir = %dir.display(), error = %e, "Could not read directory");
+            internal_error()
+        }
+    }
+}
+
+struct DirItem {
+    name: String,
+    path: String,
+    link_path: String,
+    is_dir: bool,
+    size: Option<u64>,
+    modified: Option<SystemTime>,
+}
+
+impl DirItem {
+    fn new(name: String, base: &str, prefix: &str, metadata: Option<&Metadata>) -> Self {
+        let path = if base.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", base, &name)
+        };
+        let link_path = format!("{}/files/{}", prefix, encode_url_path(&path));
+
+        DirItem {
+            name,
+            path,
+            link_path,
+            is_dir: metadata.is_some_and(|m| m.is_dir()),
+            size: metadata.filter(|m| !m.is_dir()).map(|m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()),
+        }
+    }
+
+    fn to_entry(&self) -> ListEntry {
+        ListEntry {
+            name: self.name.clone(),
+            url: self.link_path.clone(),
+            is_dir: self.is_dir,
+            size: self.size,
+            modified: self.modified.map(httpdate::fmt_http_date),
+            mime: (!self.is_dir).then(|| {
+                mime_guess::from_path(&self.name)
+                    .first_or_octet_stream()
+                    .to_string()
+            }),
+        }
+    }
+
+    /// One listing row. Names come from the disk, so everything is escaped.
+    fn to_html(&self) -> String {
+        let icon = if self.is_dir { "📁" } else { "📄" };
+        let size = self.size.map(format_size).unwrap_or_default();
+        let modified = self
+            .modified
+            .map(httpdate::fmt_http_date)
+            .unwrap_or_default();
+        format!(
+            r#"<div class="item-row">
+                <div class="checkbox-wrapper">
+                    <input type="checkbox" class="item-check" data-path="{path}" data-is-dir="{is_dir}">
+                </div>
+                <span class="icon">{icon}</span>
+                <a class="file-link" href="{link_path}">{name}</a>
+                <span class="meta size">{size}</span>
+                <span class="meta modified">{modified}</span>
+            </div>"#,
+            path = escape_html(&self.path),
+            is_dir = self.is_dir,
+            icon = icon,
+            link_path = escape_html(&self.link_path),
+            name = escape_html(&self.name),
+            size = size,
+            modified = modified
+        )
+    }
+}
+
+fn generate_page_html_template(
+    parent: Option<String>,
+    items: Vec<DirItem>,
+    prefix: &str,
+) -> String {
+    let mut content = String::new();
+
+    // Parent directory row
+    if let Some(parent_path) = parent {
+        content.push_str(&format!(
+            r#"<div class="item-row parent-row">
+                <a href="{}/files/{}" style="text-decoration:none; color:#666;">⤴ .. (Parent Directory)</a>
+            </div>"#,
+            escape_html(prefix),
+            encode_url_path(&parent_path)
+        ));
+    }
+
+    // File and folder rows
+    for item in items {
+        content.push_str(&item.to_html());
+    }
+
+    // Inject into HTML template
+    // Fill in the prefix first so file names can never be mistaken for placeholders
+    let html_template = include_str!("../index.html");
+    html_template
+        .replace("{{PREFIX}}", prefix)
+        .replace("{{CONTENT}}", &content)
+}
+
+// ==========================
+// Remote Proxy
+// ==========================
+/// A `--proxy` URL, downloaded into a temp folder on first request and served
+/// from there afterwards. A failed fetch is not cached, so the next request retries.
+struct RemoteFile {
+    url: reqwest::Url,
+    client: reqwest::Client,
+    dir: PathBuf,
+    cached: tokio::sync::Mutex<Option<CachedRemote>>,
+}
+
+#[derive(Clone)]
+struct CachedRemote {
+    /// Named after the remote file, so the landing page and downloads pick it up
+    path: PathBuf,
+    content_type: Option<String>,
+}
+
+impl RemoteFile {
+    fn new(url: &str) -> io::Result<Self> {
+        let url = reqwest::Url::parse(url)
+            .ok()
+            .filter(|u| matches!(u.scheme(), "http" | "https"))
+            .ok_or_else(|| {
+                io::Error::other(format!(
+                    "Could not proxy {}. Details: expected an http(s) URL",
+                    url
+                ))
+            })?;
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("wfpush/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| context(e, "Could not create the HTTP client".to_string()))?;
+
+        Ok(RemoteFile {
+            url,
+            client,
+            dir: env::temp_dir().join(format!("wfpush-{}", Uuid::new_v4())),
+            cached: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// Returns the cached copy, fetching it first if needed. Upstream error
+    /// statuses are passed through; transport failures become 502.
+    async fn fetch(&self) -> Result<CachedRemote, StatusCode> {
+        // Held for the whole download so concurrent first requests share one fetch
+        let mut cached = self.cached.lock().await;
+        if let Some(cached) = cached.as_ref() {
+            return Ok(cached.clone());
+        }
+
+        let response = self
+            .client
+            .get(self.url.clone())
+            .send()
+            .await
+            .map_err(|e| {
+                warn!(url = %self.url, error = %e, "Could not reach the proxied URL");
+                StatusCode::BAD_GATEWAY
+            })?;
+        if !response.status().is_success() {
+            warn!(url = %self.url, status = %response.status(), "Proxied URL returned an error");
+            return Err(response.status());
+        }
+
+        let name = remote_file_name(&response);
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let path = self.dir.join(&name);
+
+        let saved = async {
+            fs::create_dir_all(&self.dir).await?;
+            let mut file = File::create(&path).await?;
+            let mut body = response.bytes_stream();
+            while let Some(chunk) = body.next().await {
+                file.write_all(&chunk.map_err(io::Error::other)?).await?;
+            }
+            file.flush().await
+        };
+        if let Err(e) = saved.await {
+            warn!(url = %self.url, error = %e, "Could not cache the proxied URL");
+            let _ = fs::remove_file(&path).await;
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        info!(url = %self.url, file = %path.display(), "Cached proxied URL");
+
+        let remote = CachedRemote { path, content_type };
+        *cached = Some(remote.clone());
+        Ok(remote)
+    }
+}
+
+/// The upstream's `Content-Disposition` filename, else the decoded last URL
+/// segment, whichever survives [`sanitize_filename`] first.
+fn remote_file_name(response: &reqwest::Response) -> String {
+    let from_header = response
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| {
+            v.split(';')
+                .filter_map(|part| part.trim().strip_prefix("filename="))
+                .map(|name| name.trim_matches('"').to_string())
+                .next()
+        });
+    let from_url = response
+        .url()
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(decode_url_segment);
+
+    // Both are up to the upstream, so neither may name a path outside the cache folder
+    [from_header, from_url]
+        .into_iter()
+        .flatten()
+        .map(|name| sanitize_filename(&name))
+        .find(|name| !name.is_empty())
+        .unwrap_or_else(|| "download".to_string())
+}
+
+// ==========================
+// Landing Page
+// ==========================
+/// A friendlier front page for single-file shares than an immediate download.
+/// The suggested command suits the visitor's platform unless `--hint` fixed it.
+async fn render_landing_page(
+    state: &AppState,
+    file: &Path,
+    headers: &HeaderMap,
+) -> impl IntoResponse {
+    let name = match &state.download_name {
+        Some(name) => name.clone(),
+        None => file_display_name(file, "download"),
+    };
+    let size = fs::metadata(file)
+        .await
+        .map(|m| format_size(m.len()))
+        .unwrap_or_default();
+    let windows = headers
+        .get(header::USER_AGENT)
+        .and_then(|ua| ua.to_str().ok())
+        .is_some_and(|ua| ua.contains("Windows"));
+    let hint = state.hint.unwrap_or(if windows {
+        DownloadHint::PowerShell
+    } else {
+        DownloadHint::Curl
+    });
+    let url = format!("{}{}", state.host, state.download_route);
+    let command = download_command(hint, &url, &name, state.self_signed);
+
+    let html_template = include_str!("../download.html");
+    Html(
+        html_template
+            .replace("{{NAME}}", &escape_html(&name))
+            .replace("{{SIZE}}", &size)
+            .replace(
+                "{{DOWNLOAD}}",
+                &format!("{}{}", state.path_prefix, state.download_route),
+            )
+            .replace("{{CURL}}", &escape_html(&command)),
+    )
+}
+
+/// A command that saves `url` as `name` in the shell `hint` is for. `insecure`
+/// skips certificate checks for self-signed TLS.
+fn download_command(hint: DownloadHint, url: &str, name: &str, insecure: bool) -> String {
+    match hint {
+        DownloadHint::Curl => {
+            let insecure = if insecure { " -k" } else { "" };
+            format!("curl{} -OJ \"{}\"", insecure, url)
+        }
+        DownloadHint::PowerShell => {
+            // Single quotes keep `$` and backticks literal; a quote is escaped by doubling it
+            let quote = |s: &str| format!("'{}'", s.replace('\'', "''"));
+            let insecure = if insecure {
+                " -SkipCertificateCheck"
+            } else {
+                ""
+            };
+            format!(
+                "Invoke-WebRequest -Uri {} -OutFile {}{}",
+                quote(url),
+                quote(name),
+                insecure
+            )
+        }
+    }
+}
+
+// ==========================
+// File Serving
+// ==========================
+/// Streams `path` honoring ranges and validators. For HEAD requests the same
+/// headers are produced but no body is read, so probes never count as downloads.
+/// `name` overrides the filename offered in `Content-Disposition` and
+/// `content_type` the type guessed from the extension.
+async fn serve_file(
+    state: &AppState,
+    path: &Path,
+    name: Option<&str>,
+    content_type: Option<&str>,
+    method: &Method,
+    headers: HeaderMap,
+    client: ClientAddr,
+) -> Response {
+    let head = method == Method::HEAD;
+
+    // Held by the body until the transfer finishes; HEAD probes need no slot
+    let permit = if head {
+        None
+    } else {
+        match state.acquire_connection() {
+            Ok(permit) => permit,
+            Err(_) => return too_many_downloads(),
+        }
+    };
+
+    // Like nginx's gzip_static: a `<name>.gz` beside the file is sent as is to
+    // clients that accept gzip, while names and types still come from `path`.
+    // A range request takes precedence and is served from the original, so
+    // offsets always count identity bytes
+    let ranged = headers.contains_key(RANGE) && !state.no_ranges;
+    let variant = gzip_variant(path);
+    let gzipped = variant.is_some() && accepts_gzip(&headers) && !ranged;
+    let source = match &variant {
+        Some(gz) if gzipped => gz.as_path(),
+        _ => path,
+    };
+
+    let mut file = match File::open(source).await {
+        Ok(f) => f,
+        Err(e) => {
+            warn!(file = %source.display(), error = %e, "Could not open file");
+            return match e.kind() {
+                io::ErrorKind::NotFound => StatusCode::NOT_FOUND.into_response(),
+                io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN.into_response(),
+                _ => internal_error(),
+            };
+        }
+    };
+
+    let filename = match name {
+        Some(name) => name.to_string(),
+        None => file_display_name(path, "download"),
+    };
+    let mime = match &state.content_type {
+        Some(forced) => forced.clone(),
+        None => content_type
+            .and_then(|t| t.parse::<mime_guess::Mime>().ok())
+            .unwrap_or_else(|| mime_guess::from_path(path).first_or_octet_stream()),
+    };
+    let disposition = if state.inline && is_previewable(&mime) {
+        "inline"
+    } else {
+        "attachment"
+    };
+
+    let Some(type_value) = header_value(mime.as_ref()) else {
+        return internal_error();
+    };
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(header::CONTENT_TYPE, type_value);
+    response_headers.insert(header::CACHE_CONTROL, state.cache_control.clone());
+    // A shared page opened inline must not run script as the share's origin
+    if state.security_headers && ACTIVE_TYPES.contains(&mime.essence_str()) {
+        response_headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("sandbox"),
+        );
+    }
+    if gzipped {
+        response_headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    }
+    if variant.is_some() {
+        response_headers.insert(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response_headers.insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition(disposition, &filename),
+    );
+
+    // Without metadata, or for a pipe or device, the size is unknown, so fall
+    // back to a chunked full download
+    let metadata = match file.metadata().await {
+        Ok(m) if m.is_file() => m,
+        _ => {
+            if head {
+                let mut res = Response::new(Body::empty());
+                *res.headers_mut() = response_headers;
+                return res;
+            }
+
+            info!(
+                client = %client,
+                file = %source.display(),
+                "File download (full, unknown size)"
+            );
+
+            let body = download_body(
+                state,
+                ReaderStream::with_capacity(file, state.chunk_size),
+                None,
+                true,
+                permit,
+                &filename,
+                client,
+            );
+            let mut res = Response::new(body);
+            *res.headers_mut() = response_headers;
+            return res;
+        }
+    };
+
+    let file_size = metadata.len();
+    let modified = metadata.modified().ok();
+    let etag = entity_tag(file_size, modified);
+
+    let Some(etag_value) = header_value(&etag) else {
+        return internal_error();
+    };
+    // Second precision matches If-Modified-Since, which is compared the same way
+    let last_modified = modified.and_then(|m| header_value(&httpdate::fmt_http_date(m)));
+    let accept_ranges = if state.no_ranges { "none" } else { "bytes" };
+    response_headers.insert(
+        header::ACCEPT_RANGES,
+        HeaderValue::from_static(accept_ranges),
+    );
+    response_headers.insert(header::ETAG, etag_value.clone());
+    if let Some(last_modified) = &last_modified {
+        response_headers.insert(header::LAST_MODIFIED, last_modified.clone());
+    }
+
+    if is_not_modified(&headers, &etag, modified) {
+        let mut res = StatusCode::NOT_MODIFIED.into_response();
+        res.headers_mut().insert(header::ETAG, etag_value);
+        res.headers_mut()
+            .insert(header::CACHE_CONTROL, state.cache_control.clone());
+        if let Some(last_modified) = last_modified {
+            res.headers_mut()
+                .insert(header::LAST_MODIFIED, last_modified);
+        }
+        return res;
+    }
+
+    // Under --no-ranges a Range header is ignored, as RFC 9110 allows, and the
+    // whole file is sent with 200. So is one whose If-Range names an older
+    // version, since its offsets were taken from a file that is gone
+    if let Some(range_header) = headers
+        .get(RANGE)
+        .filter(|_| ranged && if_range_matches(&headers, &etag, modified))
+    {
+        let Some(ranges) = parse_ranges(range_header.to_str().unwrap_or(""), file_size) else {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", file_size))],
+            )
+                .into_response();
+        };
+
+        if ranges.len() > 1 {
+            let boundary = Uuid::new_v4().simple().to_string();
+            let Some(multipart_type) =
+                header_value(&format!("multipart/byteranges; boundary={}", boundary))
+            else {
+                return internal_error();
+            };
+            let (length, stream) = byteranges_body(
+                source,
+                &ranges,
+                file_size,
+                mime.as_ref(),
+                &boundary,
+                state.chunk_size,
+            );
+            let body = if head {
+                Body::empty()
+            } else {
+                info!(
+                    client = %client,
+                    file = %source.display(),
+                    ranges = ranges.len(),
+                    "File download (multiple ranges)"
+                );
+                download_body(
+                    state,
+                    stream,
+                    Some(length),
+                    false,
+                    permit,
+                    &filename,
+                    client,
+                )
+            };
+
+            response_headers.insert(header::CONTENT_TYPE, multipart_type);
+            response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+            let mut res = Response::new(body);
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+            *res.headers_mut() = response_headers;
+            return res;
+        }
+        let (start, end) = ranges[0];
+
+        let Some(content_range) = header_value(&format!("bytes {}-{}/{}", start, end, file_size))
+        else {
+            return internal_error();
+        };
+
+        let length = end - start + 1;
+        let body = if head {
+            Body::empty()
+        } else {
+            info!(
+                client = %client,
+                file = %source.display(),
+                range = %format!("{}-{}", start, end),
+                "File download (partial)"
+            );
+
+            file.seek(SeekFrom::Start(start)).await.ok();
+
+            // A range reaching the end of the file completes a (possibly resumed) download
+            let stream = ReaderStream::with_capacity(file.take(length), state.chunk_size);
+            download_body(
+                state,
+                stream,
+                Some(length),
+                end + 1 == file_size,
+                permit,
+                &filename,
+                client,
+            )
+        };
+
+        response_headers.insert(header::CONTENT_RANGE, content_range);
+        response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+
+        let mut res = Response::new(body);
+        *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+        *res.headers_mut() = response_headers;
+        return res;
+    }
+
+    // A growing file has no final length or digest, and never counts as completed
+    if state.follow {
+        let body = if head {
+            Body::empty()
+        } else {
+            info!(
+                client = %client,
+                file = %source.display(),
+                "File download (following)"
+            );
+            download_body(
+                state,
+                follow_stream(file, state.chunk_size, state.follow_timeout),
+                None,
+                false,
+                permit,
+                &filename,
+                client,
+            )
+        };
+        let mut res = Response::new(body);
+        *res.headers_mut() = response_headers;
+        res.extensions_mut().insert(Live);
+        return res;
+    }
+
+    // Never hold a download back for hashing; the Digest header appears once it is known
+    let digest = state.checksums.lock().unwrap().get(source).copied();
+    if state.checksum && digest.is_none() && file_size <= state.hash_threshold {
+        spawn_checksum(state, source);
+    }
+    if let Some(digest) =
+        digest.and_then(|d| header_value(&format!("sha-256={}", BASE64.encode(d))))
+    {
+        response_headers.insert(HeaderName::from_static("digest"), digest);
+    }
+
+    let body = if head {
+        Body::empty()
+    } else {
+        info!(
+            client = %client,
+            file = %source.display(),
+            size = file_size,
+            "File download (full)"
+        );
+        download_body(
+            state,
+            ReaderStream::with_capacity(file, state.chunk_size),
+            Some(file_size),
+            true,
+            permit,
+            &filename,
+            client,
+        )
+    };
+    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(file_size));
+
+    let mut res = Response::new(body);
+    *res.headers_mut() = response_headers;
+    res
+}
+
+/// The precompressed `<path>.gz` next to `path`, if there is one. Symlinks
+/// are ignored so the variant cannot lead outside the share.
+fn gzip_variant(path: &Path) -> Option<PathBuf> {
+    let mut name = path.file_name()?.to_os_string();
+    name.push(".gz");
+    let gz = path.with_file_name(name);
+    std::fs::symlink_metadata(&gz)
+        .is_ok_and(|m| m.is_file())
+        .then_some(gz)
+}
+
+/// Whether `Accept-Encoding` lists gzip without ruling it out via `q=0`.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or("");
+            let refused = parts.any(|p| {
+                p.strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            name.eq_ignore_ascii_case("gzip") && !refused
+        })
+}
+
+/// Types a browser can display on its own, so `--inline` is worth honoring.
+fn is_previewable(mime: &mime_guess::Mime) -> bool {
+    matches!(mime.type_().as_str(), "text" | "image" | "audio" | "video")
+        || matches!(mime.essence_str(), "application/pdf" | "application/json")
+}
+
+/// Sent when every `--max-connections` slot is taken.
+fn too_many_downloads() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "5")],
+        "Too many active downloads, try again shortly\n",
+    )
+        .into_response()
+}
+
+/// Content types that are already compressed and gain nothing from gzip.
+/// Images are excluded by tower-http's default predicate.
+const PRECOMPRESSED_TYPES: &[&str] = &[
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+    "application/zstd",
+    "audio/",
+    "video/",
+];
+
+/// Ranges and compression never mix: a range is always served from the
+/// identity bytes, so any 206 is left alone (tower-http only skips those with
+/// a single `Content-Range`, not `multipart/byteranges`). When a full
+/// response is compressed, tower-http drops `Accept-Ranges`, so clients
+/// don't try to resume the gzip stream with offsets into the original.
+fn compress_predicate() -> impl Predicate {
+    DefaultPredicate::new().and(
+        |status: StatusCode, _: Version, headers: &HeaderMap, extensions: &Extensions| {
+            let content_type = headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            status != StatusCode::PARTIAL_CONTENT
+                && !is_precompressed(content_type)
+                && extensions.get::<Live>().is_none()
+        },
+    )
+}
+
+/// Marks a response whose bytes must reach the client as soon as they exist.
+/// The gzip encoder holds output back until it has a full block, which would
+/// stall a followed file.
+#[derive(Clone, Copy)]
+struct Live;
+
+fn is_precompressed(content_type: &str) -> bool {
+    PRECOMPRESSED_TYPES
+        .iter()
+        .any(|ty| content_type.starts_with(ty))
+}
+
+// ==========================
+// Download Bodies
+// ==========================
+type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Wraps a file stream with the per-download behaviour configured on the
+/// command line. `completes` marks bodies that finish a download when fully sent,
+/// `permit` is released once the body is dropped, and `file` and `client` name
+/// the transfer in its progress bar and summary line.
+fn download_body<S>(
+    state: &AppState,
+    stream: S,
+    length: Option<u64>,
+    completes: bool,
+    permit: Option<OwnedSemaphorePermit>,
+    file: &str,
+    client: ClientAddr,
+) -> Body
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    let counted = Counted::new(Box::pin(stream) as ByteStream, state);
+    let mut stream: ByteStream = Box::pin(Summarized::new(counted, length, file, client));
+    if let Some(permit) = permit {
+        stream = Box::pin(Permitted {
+            inner: stream,
+            _permit: permit,
+        });
+    }
+    if completes {
+        stream = Box::pin(TrackedStream::new(
+            stream,
+            length,
+            state.clone(),
+            file,
+            client,
+        ));
+    }
+    if let Some(rate) = state.limit_rate {
+        stream = Box::pin(Throttled::new(stream, rate, state.throttle_after));
+    }
+    if let Some(timeout) = state.stall_timeout {
+        stream = stall_guard(stream, timeout, file, client);
+    }
+    if let Some(progress) = &state.progress {
+        let label = format!("{} -> {}", file, client);
+        stream = Box::pin(Progress::new(stream, progress, length, &label));
+    }
+    Body::from_stream(stream)
+}
+
+/// Streams `file` from the start and then, like `tail -f`, whatever is
+/// appended to it, checking for new bytes a few times a second. It ends once
+/// the file has not grown for `idle`, if set. A file that shrinks was
+/// truncated or rotated, so reading starts over from its beginning.
+fn follow_stream(
+    file: File,
+    chunk_size: usize,
+    idle: Option<Duration>,
+) -> impl Stream<Item = io::Result<Bytes>> + Send + 'static {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    stream::unfold(
+        (file, 0u64, Instant::now()),
+        move |(mut file, mut position, mut grew)| async move {
+            let mut buf = vec![0; chunk_size];
+            loop {
+                match file.read(&mut buf).await {
+                    Ok(0) => {}
+                    Ok(n) => {
+                        buf.truncate(n);
+                        let state = (file, position + n as u64, Instant::now());
+                        return Some((Ok(Bytes::from(buf)), state));
+                    }
+                    Err(e) => return Some((Err(e), (file, position, grew))),
+                }
+                if idle.is_some_and(|idle| grew.elapsed() >= idle) {
+                    return None;
+                }
+                if file.metadata().await.is_ok_and(|m| m.len() < position) {
+                    if let Err(e) = file.seek(SeekFrom::Start(0)).await {
+                        return Some((Err(e), (file, position, grew)));
+                    }
+                    position = 0;
+                    grew = Instant::now();
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        },
+    )
+}
+
+/// Moves `stream` onto its own task that feeds the body one chunk at a time.
+/// A client that stops reading leaves the task unable to hand over the next
+/// chunk; after `timeout` of that it gives up, dropping the open file and any
+/// `--max-connections` slot, which a stalled body would otherwise hold forever.
+/// The body then ends in a `TimedOut` error rather than cleanly, so a chunked
+/// response without a length to check is still aborted, not taken as complete.
+fn stall_guard(
+    stream: ByteStream,
+    timeout: Duration,
+    file: &str,
+    client: ClientAddr,
+) -> ByteStream {
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    let stalled = Arc::new(AtomicBool::new(false));
+    let file = file.to_string();
+    let flag = stalled.clone();
+    tokio::spawn(async move {
+        let mut stream = stream;
+        while let Some(chunk) = stream.next().await {
+            match tokio::time::timeout(timeout, tx.send(chunk)).await {
+                Ok(Ok(())) => {}
+                // The body was dropped, so the client is already gone
+                Ok(Err(_)) => return,
+                Err(_) => {
+                    warn!(
+                        client = %client,
+                        file = %file,
+                        timeout_secs = timeout.as_secs(),
+                        "Download stalled, aborting"
+                    );
+                    flag.store(true, Ordering::SeqCst);
+                    return;
+                }
+            }
+        }
+    });
+
+    Box::pin(stream::unfold(Some(rx), move |rx| {
+        let stalled = stalled.clone();
+        async move {
+            let mut rx = rx?;
+            match rx.recv().await {
+                Some(chunk) => Some((chunk, Some(rx))),
+                None if stalled.load(Ordering::SeqCst) => Some((
+                    Err(io::Error::new(io::ErrorKind::TimedOut, "download stalled")),
+                    None,
+                )),
+                None => None,
+            }
+        }
+    }))
+}
+
+/// Paces a body to roughly `rate` bytes per second with a token bucket. The
+/// first `burst` bytes of the body go out at full speed, once; everything
+/// after that spends tokens that refill at `rate`, and a chunk that runs the
+/// bucket into debt is held back until it is paid off. The bucket never
+/// holds more than the chunk being sent, so a client that stalls cannot save
+/// up for a second burst.
+struct Throttled<S> {
+    inner: S,
+    rate: f64,
+    burst_left: u64,
+    tokens: f64,
+    refilled: Instant,
+    held: Option<(Bytes, Pin<Box<Sleep>>)>,
+}
+
+impl<S> Throttled<S> {
+    fn new(inner: S, rate: u64, burst: u64) -> Self {
+        Self {
+            inner,
+            rate: rate as f64,
+            burst_left: burst,
+            tokens: 0.0,
+            refilled: Instant::now(),
+            held: None,
+        }
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for Throttled<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Some((_, delay)) = self.held.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            let (chunk, _) = self.held.take().unwrap();
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        let chunk = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+            Some(Ok(chunk)) => chunk,
+            other => return Poll::Ready(other),
+        };
+        let now = Instant::now();
+        let free = (chunk.len() as u64).min(self.burst_left);
+        self.burst_left -= free;
+        let paced = (chunk.len() as u64 - free) as f64;
+        let earned = now.duration_since(self.refilled).as_secs_f64() * self.rate;
+        self.refilled = now;
+        if paced == 0.0 {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        self.tokens = (self.tokens + earned).min(paced) - paced;
+        if self.tokens >= 0.0 {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        let wait = Duration::from_secs_f64(-self.tokens / self.rate);
+        self.held = Some((chunk, Box::pin(tokio::time::sleep(wait))));
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// ==========================
+// Download Tracking
+// ==========================
+/// Logs a one-line summary with the bytes sent, time taken and average rate
+/// once a transfer ends, marking it partial when the client went away early.
+struct Summarized<S> {
+    inner: S,
+    file: String,
+    client: ClientAddr,
+    started: Instant,
+    sent: u64,
+    remaining: Option<u64>,
+    finished: bool,
+}
+
+impl<S> Summarized<S> {
+    fn new(inner: S, length: Option<u64>, file: &str, client: ClientAddr) -> Self {
+        Self {
+            inner,
+            file: file.to_string(),
+            client,
+            started: Instant::now(),
+            sent: 0,
+            remaining: length,
+            finished: false,
+        }
+    }
+
+    fn summarize(&mut self, complete: bool) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        let elapsed = self.started.elapsed();
+        let rate = (self.sent as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+        info!(
+            client = %self.client,
+            file = %self.file,
+            bytes = self.sent,
+            elapsed_ms = elapsed.as_millis() as u64,
+            rate = %format!("{}/s", format_size(rate)),
+            "{}",
+            if complete { "Download finished" } else { "Download partial" }
+        );
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for Summarized<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.sent += chunk.len() as u64;
+                if let Some(remaining) = self.remaining.as_mut() {
+                    *remaining = remaining.saturating_sub(chunk.len() as u64);
+                    if *remaining == 0 {
+                        self.summarize(true);
+                    }
+                }
+            }
+            Poll::Ready(None) => self.summarize(true),
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl<S> Drop for Summarized<S> {
+    fn drop(&mut self) {
+        self.summarize(false);
+    }
+}
+
+/// Draws a terminal progress bar for one transfer, cleared when the body is
+/// dropped whether or not the transfer finished.
+struct Progress<S> {
+    inner: S,
+    bar: ProgressBar,
+}
+
+impl<S> Progress<S> {
+    fn new(inner: S, progress: &MultiProgress, length: Option<u64>, label: &str) -> Self {
+        let bar = match length {
+            Some(length) => ProgressBar::new(length).with_style(
+                ProgressStyle::with_template(
+                    "{msg} [{bar:30}] {bytes}/{total_bytes} {bytes_per_sec} eta {eta}",
+                )
+                .unwrap()
+                .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {msg} {bytes} {bytes_per_sec}").unwrap(),
+            ),
+        };
+        Self {
+            inner,
+            bar: progress.add(bar.with_message(label.to_string())),
+        }
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for Progress<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bar.inc(chunk.len() as u64);
+        }
+        poll
+    }
+}
+
+impl<S> Drop for Progress<S> {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Feeds the `/stats` counters: the body counts as active until it is
+/// dropped, and every chunk it yields adds to the bytes served.
+struct Counted<S> {
+    inner: S,
+    bytes_served: Arc<AtomicU64>,
+    active_downloads: Arc<AtomicU64>,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S, state: &AppState) -> Self {
+        state.active_downloads.fetch_add(1, Ordering::Relaxed);
+        Self {
+            inner,
+            bytes_served: state.bytes_served.clone(),
+            active_downloads: state.active_downloads.clone(),
+        }
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for Counted<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.bytes_served
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<S> Drop for Counted<S> {
+    fn drop(&mut self) {
+        self.active_downloads.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a `--max-connections` slot occupied for as long as the body lives.
+struct Permitted<S> {
+    inner: S,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for Permitted<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+/// Wraps a download body and records it as completed once the final byte has
+/// been handed over. Hyper stops polling as soon as `Content-Length` bytes are
+/// sent, so completion is detected by counting rather than waiting for EOF.
+/// Aborted transfers drop the stream early and never count, and neither does
+/// a body that fails part way.
+struct TrackedStream<S> {
+    inner: S,
+    remaining: Option<u64>,
+    sent: u64,
+    file: String,
+    client: ClientAddr,
+    state: Option<AppState>,
+}
+
+impl<S> TrackedStream<S> {
+    /// `length` is the number of bytes the response promises, if known.
+    fn new(inner: S, length: Option<u64>, state: AppState, file: &str, client: ClientAddr) -> Self {
+        Self {
+            inner,
+            remaining: length,
+            sent: 0,
+            file: file.to_string(),
+            client,
+            state: Some(state),
+        }
+    }
+
+    fn complete(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.record_download();
+            if let Some(webhook) = &state.webhook {
+                webhook.notify(&self.file, self.client, self.sent);
+            }
+        }
+    }
+}
+
+impl<S: Stream<Item = io::Result<Bytes>> + Unpin> Stream for TrackedStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                self.sent += chunk.len() as u64;
+                if let Some(remaining) = self.remaining.as_mut() {
+                    *remaining = remaining.saturating_sub(chunk.len() as u64);
+                    if *remaining == 0 {
+                        self.complete();
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => self.state = None,
+            Poll::Ready(None) => self.complete(),
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+// ==========================
+// Webhook
+// ==========================
+/// Where `--webhook` reports completed downloads.
+struct Webhook {
+    url: reqwest::Url,
+    client: reqwest::Client,
+}
+
+/// The JSON body POSTed for each completed download.
+#[derive(Serialize)]
+struct DownloadEvent {
+    file: String,
+    client: String,
+    bytes: u64,
+    /// Seconds since the Unix epoch
+    timestamp: u64,
+}
+
+impl Webhook {
+    fn new(url: &str) -> io::Result<Self> {
+        let url = reqwest::Url::parse(url)
+            .ok()
+            .filter(|u| matches!(u.scheme(), "http" | "https"))
+            .ok_or_else(|| {
+                io::Error::other(format!(
+                    "Could not use {} as the webhook. Details: expected an http(s) URL",
+                    url
+                ))
+            })?;
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("wfpush/", env!("CARGO_PKG_VERSION")))
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| context(e, "Could not create the HTTP client".to_string()))?;
+        Ok(Webhook { url, client })
+    }
+
+    /// Posts the event in the background. The download has already been
+    /// sent, so a webhook that is down or slow only gets a log line.
+    fn notify(&self, file: &str, client: ClientAddr, bytes: u64) {
+        let event = DownloadEvent {
+            file: file.to_string(),
+            client: client.to_string(),
+            bytes,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        let Ok(body) = serde_json::to_vec(&event) else {
+            return;
+        };
+        let request = self
+            .client
+            .post(self.url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body);
+        tokio::spawn(async move {
+            match request.send().await {
+                Ok(response) if !response.status().is_success() => {
+                    warn!(status = %response.status(), "Webhook rejected the download event")
+                }
+                Ok(_) => {}
+                Err(e) => warn!(error = %e, "Could not reach the webhook"),
+            }
+        });
+    }
+}
+
+// ==========================
+// Zip Archives
+// ==========================
+/// Streams the shared folder (or every shared path) as one zip archive that
+/// is built on the fly, so memory use stays flat however big the folder is.
+async fn zip_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<ClientAddr>,
+) -> Response {
+    let Some(roots) = state.root.folder_roots() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let name = match &state.root {
+        Root::Path(dir) => file_display_name(dir, "download"),
+        _ => "download".to_string(),
+    };
+
+    let permit = match state.acquire_connection() {
+        Ok(permit) => permit,
+        Err(_) => return too_many_downloads(),
+    };
+
+    let filename = format!("{}.zip", name);
+    info!(client = %addr, archive = %filename, "Archive download");
+
+    let top_level_only = state.basename_only;
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let (failed_tx, failed_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        if let Err(e) = write_zip(writer, roots, top_level_only).await {
+            warn!(error = %e, "Archive stream failed");
+            let _ = failed_tx.send(e);
+        }
+    });
+
+    // A failed archive has no length to give it away, so it must end in an
+    // error for hyper to abort the response instead of finishing it cleanly
+    let failure = stream::once(failed_rx).filter_map(|failed| async move { failed.ok().map(Err) });
+    let stream = ReaderStream::with_capacity(reader, state.chunk_size).chain(failure);
+    let mut res = Response::new(download_body(
+        &state, stream, None, true, permit, &filename, addr,
+    ));
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    res.headers_mut().insert(
+        header::CONTENT_DISPOSITION,
+        content_disposition("attachment", &filename),
+    );
+    res
+}
+
+/// Writes every file and folder under `roots` into a zip on `writer`. Each
+/// root is stored under its name, or at the top level when the name is empty.
+/// With `top_level_only` (`--basename-only`) folders and their contents are skipped.
+async fn write_zip(
+    writer: DuplexStream,
+    roots: Vec<(String, PathBuf)>,
+    top_level_only: bool,
+) -> io::Result<()> {
+    let mut entries = tokio::task::spawn_blocking(move || zip_listing(&roots))
+        .await
+        .map_err(io::Error::other)?;
+    if top_level_only {
+        entries.retain(|item| !item.is_dir && !item.name.contains('/'));
+    }
+
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for item in entries {
+        let date = zip_date(item.modified.unwrap_or(UNIX_EPOCH));
+        if item.is_dir {
+            let entry = ZipEntryBuilder::new(format!("{}/", item.name).into(), Compression::Stored)
+                .last_modification_date(date);
+            zip.write_entry_whole(entry, &[])
+                .await
+                .map_err(io::Error::other)?;
+            continue;
+        }
+
+        let mime = mime_guess::from_path(&item.path).first_or_octet_stream();
+        let compression = if is_precompressed(mime.essence_str()) || mime.type_() == "image" {
+            Compression::Stored
+        } else {
+            Compression::Deflate
+        };
+
+        // Like a walk error, one unreadable file leaves out only itself
+        let mut file = match File::open(&item.path).await {
+            Ok(file) => file,
+            Err(e) => {
+                warn!(file = %item.path.display(), error = %e, "Leaving unreadable file out of the archive");
+                continue;
+            }
+        };
+        let entry =
+            ZipEntryBuilder::new(item.name.into(), compression).last_modification_date(date);
+        let entry = zip
+            .write_entry_stream(entry)
+            .await
+            .map_err(io::Error::other)?;
+        let mut entry = entry.compat_write();
+        tokio::io::copy(&mut file, &mut entry).await?;
+        entry.into_inner().close().await.map_err(io::Error::other)?;
+    }
+    zip.close().await.map_err(io::Error::other)?;
+    Ok(())
+}
+
+struct ZipItem {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+    modified: Option<SystemTime>,
+}
+
+/// Lists archive entries in a stable order. Symlinks are skipped so nothing
+/// outside the shared paths ends up inside.
+fn zip_listing(roots: &[(String, PathBuf)]) -> Vec<ZipItem> {
+    let mut entries = Vec::new();
+    for (prefix, root) in roots {
+        let walk = WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != PARTIAL_DIR);
+        for entry in walk {
+            let Ok(entry) = entry else { continue };
+            // Pipes and devices could keep the archive open forever
+            let file_type = entry.file_type();
+            if entry.path_is_symlink() || !(file_type.is_dir() || file_type.is_file()) {
+                continue;
+            }
+
+            let relative: Vec<String> = entry
+                .path()
+                .strip_prefix(root)
+                .unwrap_or(entry.path())
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            let name = std::iter::once(prefix.clone())
+                .chain(relative)
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join("/");
+            if name.is_empty() {
+                continue;
+            }
+            entries.push(ZipItem {
+                name,
+                path: entry.path().to_path_buf(),
+                is_dir: entry.file_type().is_dir(),
+                modified: entry.metadata().ok().and_then(|m| m.modified().ok()),
+            });
+        }
+    }
+    entries
+}
+
+/// Converts a timestamp to the calendar fields zip entries store (UTC,
+/// clamped to the 1980 start of the zip epoch).
+fn zip_date(time: SystemTime) -> ZipDateTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+        .max(315_532_800);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Civil-from-days, after Howard Hinnant's date algorithms
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = (yoe + era * 400 + i64::from(month <= 2)) as i32;
+
+    ZipDateTimeBuilder::new()
+        .year(year)
+        .month(month)
+        .day(day)
+        .hour((rem / 3_600) as u32)
+        .minute((rem % 3_600 / 60) as u32)
+        .second((rem % 60) as u32)
+        .build()
+}
+
+// ==========================
+// Checksums
+// ==========================
+/// Returns the SHA-256 of a file, hashing it in chunks on first use and
+/// serving later requests from the cache in `AppState`.
+async fn file_checksum(state: &AppState, path: &Path) -> io::Result<[u8; 32]> {
+    if let Some(digest) = state.checksums.lock().unwrap().get(path) {
+        return Ok(*digest);
+    }
+
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    let digest: [u8; 32] = hasher.finalize().into();
+    state
+        .checksums
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), digest);
+    Ok(digest)
+}
+
+/// Where `--checksum-file` puts the sidecar for `path`: `<name>.sha256` beside it.
+fn checksum_file_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".sha256");
+    path.with_file_name(name)
+}
+
+/// Writes the `<hash>  <name>` line `sha256sum -c` expects, returning the
+/// sidecar's path and the hex digest.
+async fn write_checksum_file(state: &AppState, path: &Path) -> io::Result<(PathBuf, String)> {
+    let digest = to_hex(&file_checksum(state, path).await?);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let sidecar = checksum_file_path(path);
+    fs::write(&sidecar, format!("{}  {}\n", digest, name)).await?;
+    Ok((sidecar, digest))
+}
+
+/// Hashes `path` in a background task unless it is cached or already in progress.
+fn spawn_checksum(state: &AppState, path: &Path) {
+    if state.checksums.lock().unwrap().contains_key(path)
+        || !state.hashing.lock().unwrap().insert(path.to_path_buf())
+    {
+        return;
+    }
+
+    let state = state.clone();
+    let path = path.to_path_buf();
+    tokio::spawn(async move {
+        if let Err(e) = file_checksum(&state, &path).await {
+            warn!(file = %path.display(), error = %e, "Checksum failed");
+        }
+        state.hashing.lock().unwrap().remove(&path);
+    });
+}
+
+fn is_hashing(state: &AppState, path: &Path) -> bool {
+    state.hashing.lock().unwrap().contains(path)
+}
+
+/// Tells clients to come back once a background hash has finished.
+fn checksum_pending() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "5")],
+        "Checksum is still being computed, try again shortly\n",
+    )
+        .into_response()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// ==========================
+// File Watching
+// ==========================
+/// Watches everything under the root and forgets cached checksums for
+/// changed files. Single files are watched through their parent folder so
+/// that atomic replacements (write to temp, then rename) are noticed too.
+fn watch_root(state: &AppState) -> notify::Result<RecommendedWatcher> {
+    let checksums = state.checksums.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let Ok(event) = event else { return };
+        if matches!(event.kind, EventKind::Access(_)) {
+            return;
+        }
+        let mut checksums = checksums.lock().unwrap();
+        for changed in &event.paths {
+            let before = checksums.len();
+            checksums.retain(|path, _| !path.starts_with(changed));
+            if checksums.len() != before {
+                info!(file = %changed.display(), "File changed, cached metadata reset");
+            }
+        }
+    })?;
+
+    for path in state.root.paths() {
+        match path.parent() {
+            Some(parent) if path.is_file() => watcher.watch(parent, RecursiveMode::NonRecursive)?,
+            _ => watcher.watch(path, RecursiveMode::Recursive)?,
+        }
+    }
+    Ok(watcher)
+}
+
+// ==========================
+// Conditional Requests
+// ==========================
+/// Builds a strong validator from size and modification time, which changes
+/// whenever the file is rewritten without having to hash its contents.
+fn entity_tag(size: u64, modified: Option<SystemTime>) -> String {
+    let nanos = modified
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    format!("\"{:x}-{:x}\"", size, nanos)
+}
+
+/// Whether a range request may be served as one (RFC 9110, section 13.1.5):
+/// there is no `If-Range`, or it names the current entity tag or the exact
+/// modification time. Entity tags are compared strongly, so `W/` never matches.
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str().map(str::trim) else {
+        return false;
+    };
+    if value.starts_with('"') || value.starts_with("W/") {
+        return value == etag;
+    }
+
+    match (httpdate::parse_http_date(value), modified) {
+        (Ok(date), Some(modified)) => {
+            httpdate::fmt_http_date(modified) == httpdate::fmt_http_date(date)
+        }
+        _ => false,
+    }
+}
+
+/// Applies `If-None-Match`, falling back to `If-Modified-Since` only when no
+/// entity tags were sent (RFC 9110, section 13.2.2).
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+        return if_none_match.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+        });
+    }
+
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+
+    match (since, modified) {
+        // HTTP dates have whole-second precision, so compare at that granularity
+        (Some(since), Some(modified)) => match modified.duration_since(since) {
+            Ok(newer_by) => newer_by.as_secs() == 0,
+            Err(_) => true,
+        },
+        _ => false,
+    }
+}
+
+// ==========================
+// Range Parsing
+// ==========================
+/// Parses a `bytes=` range set, clamping oversized ends to the last byte.
+/// Unsatisfiable ranges are dropped and the rest are sorted with overlapping
+/// or adjacent ones merged. Returns `None` when the header is malformed or no
+/// range can be satisfied.
+fn parse_ranges(header: &str, size: u64) -> Option<Vec<(u64, u64)>> {
+    let specs = header.strip_prefix("bytes=")?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        if let Some(range) = parse_range_spec(spec.trim(), size)? {
+            ranges.push(range);
+        }
+    }
+
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    (!merged.is_empty()).then_some(merged)
+}
+
+/// Parses one `start-end` spec. The outer `None` means malformed, the inner
+/// one that the range lies outside the file.
+fn parse_range_spec(spec: &str, size: u64) -> Option<Option<(u64, u64)>> {
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = if start.is_empty() {
+        // Suffix range: the final N bytes of the file
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 || size == 0 {
+            return Some(None);
+        }
+        (size.saturating_sub(suffix), size - 1)
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            end.parse::<u64>().ok()?
+        };
+        if start > end {
+            return None;
+        }
+        if start >= size {
+            return Some(None);
+        }
+        (start, end.min(size - 1))
+    };
+
+    Some(Some((start, end)))
+}
+
+/// Builds a `multipart/byteranges` body for `ranges` of `path`, returning its
+/// exact length alongside a stream that reads each slice in turn.
+fn byteranges_body(
+    path: &Path,
+    ranges: &[(u64, u64)],
+    size: u64,
+    content_type: &str,
+    boundary: &str,
+    chunk_size: usize,
+) -> (u64, impl Stream<Item = io::Result<Bytes>> + Send + 'static) {
+    let parts: Vec<(Bytes, u64, u64)> = ranges
+        .iter()
+        .enumerate()
+        .map(|(i, &(start, end))| {
+            let separator = if i == 0 { "" } else { "\r\n" };
+            let head = format!(
+                "{}--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                separator, boundary, content_type, start, end, size
+            );
+            (Bytes::from(head), start, end)
+        })
+        .collect();
+    let closing = Bytes::from(format!("\r\n--{}--\r\n", boundary));
+
+    let length = parts
+        .iter()
+        .map(|(head, start, end)| head.len() as u64 + end - start + 1)
+        .sum::<u64>()
+        + closing.len() as u64;
+
+    let path = path.to_path_buf();
+    let slices = stream::iter(parts)
+        .then(move |(head, start, end)| {
+            let path = path.clone();
+            async move {
+                let mut file = File::open(&path).await?;
+                file.seek(SeekFrom::Start(start)).await?;
+                let slice = ReaderStream::with_capacity(file.take(end - start + 1), chunk_size);
+                Ok::<_, io::Error>(stream::once(future::ready(Ok(head))).chain(slice))
+            }
+        })
+        .try_flatten();
+
+    (
+        length,
+        slices.chain(stream::once(future::ready(Ok(closing)))),
+    )
+}
+
+// ==========================
+// Directory Expansion
+// ==========================
+/// Lists the files below each selected folder. Folders are found with
+/// `AppState::locate`, and symlinks met on the walk are left out unless
+/// `--serve-symlinks` is set, just as they are in listings.
+async fn expand_dirs(state: &AppState, dirs: Vec<String>) -> Vec<String> {
+    let mut result = Vec::new();
+
+    for dir in dirs {
+        let Ok(top) = state.locate(&dir) else {
+            continue;
+        };
+        // Results are reported relative to the served root, i.e. prefixed by `dir`
+        let prefix = sanitize_path(&dir);
+        let mut stack = vec![top.clone()];
+
+        while let Some(path) = stack.pop() {
+            let mut rd = match fs::read_dir(&path).await {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+            while let Ok(Some(entry)) = rd.next_entry().await {
+                let p = entry.path();
+                let Ok(file_type) = entry.file_type().await else {
+                    continue;
+                };
+                if (file_type.is_symlink() && !state.serve_symlinks)
+                    || entry.file_name() == PARTIAL_DIR
+                {
+                    continue;
+                }
+                if file_type.is_dir() {
+                    stack.push(p);
+                } else if let Ok(rel) = p.strip_prefix(&top) {
+                    result.push(prefix.join(rel).to_string_lossy().to_string());
+                }
+            }
+        }
+    }
+
+    result.sort();
+    result
+}
+
+/// Gives every path a unique top-level name, suffixing repeated basenames
+/// with an index (`notes.txt`, `notes-2.txt`, ...).
+fn name_entries(paths: Vec<PathBuf>) -> BTreeMap<String, PathBuf> {
+    let mut entries = BTreeMap::new();
+
+    for path in paths {
+        let name = file_display_name(&path, "root");
+
+        let mut unique = name.clone();
+        let mut index = 2;
+        while entries.contains_key(&unique) {
+            let p = Path::new(&name);
+            unique = match (p.file_stem(), p.extension()) {
+                (Some(stem), Some(ext)) => format!(
+                    "{}-{}.{}",
+                    stem.to_string_lossy(),
+                    index,
+                    ext.to_string_lossy()
+                ),
+                _ => format!("{}-{}", name, index),
+            };
+            index += 1;
+        }
+
+        if unique != name {
+            warn!(
+                "{} is served as \"{}\" to avoid a name clash",
+                path.display(),
+                unique
+            );
+        }
+        entries.insert(unique, path);
+    }
+
+    entries
+}
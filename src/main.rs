@@ -1,465 +1,697 @@
 // ==========================
 // Imports
 // ==========================
-use axum::{
-    Json, Router,
-    body::Body,
-    extract::{ConnectInfo, Path as AxumPath, State},
-    http::{
-        HeaderMap, HeaderValue, StatusCode,
-        header::{self, RANGE},
-    },
-    response::{Html, IntoResponse, Response},
-    routing::{get, post},
-};
-use clap::Parser;
-use lru::LruCache;
-use serde::{Deserialize, Serialize};
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser, ValueEnum, parser::ValueSource};
+use ipnet::IpNet;
 use std::{
     env,
-    net::SocketAddr,
-    path::{Component, Path, PathBuf},
-    sync::{Arc, Mutex},
+    io::{self, BufRead, IsTerminal, Write},
+    net::IpAddr,
+    path::Path,
+    path::PathBuf,
+    time::Duration,
 };
 use tokio::{
     fs::{self, File},
-    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+    io::AsyncWriteExt,
 };
-use tokio_util::io::ReaderStream;
-use tracing::info;
-use unicase::UniCase;
 use uuid::Uuid;
+use wifi_file_server::{Credentials, DownloadHint, ServerConfig, UploadConflict, format_size};
 
 // ==========================
 // CLI Input Struct
 // ==========================
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
+#[command(group(ArgGroup::new("stop_condition").args(["timeout", "max_downloads"]).multiple(true)))]
 struct Args {
-    /// Root folder path of file server
-    #[arg(short, long, default_value_t = env::current_dir().expect("Invalid current directory").to_string_lossy().into_owned())]
-    folder_path: String,
+    /// Files or folders to serve; several paths are listed side by side at the root.
+    /// Use "-" to serve whatever is piped on stdin
+    paths: Vec<String>,
+
+    /// Also serve named pipes, devices and other special files, streamed without a length
+    #[arg(long)]
+    allow_special: bool,
+
+    /// Follow symlinks inside shared folders when they stay within the share.
+    /// Without this they are hidden from listings and answer 403
+    #[arg(long)]
+    serve_symlinks: bool,
+
+    /// Download filename for content read from stdin
+    #[arg(long, value_name = "NAME", default_value = "stdin", value_parser = parse_file_name)]
+    stdin_name: String,
+
+    /// Download filename to offer for a single shared file instead of its on-disk name
+    #[arg(long, value_name = "FILENAME", value_parser = parse_file_name)]
+    name: Option<String>,
+
+    /// Send this Content-Type for every file instead of guessing it from the name,
+    /// e.g. application/wasm
+    #[arg(long, value_name = "MIME")]
+    content_type: Option<String>,
+
+    /// Path a single shared file is downloaded from, e.g. /files/report.pdf
+    #[arg(long, value_name = "PATH", default_value = "/download")]
+    route: String,
+
+    /// Root folder path of file server (defaults to the current directory)
+    #[arg(short, long, env = "WFS_FILE")]
+    folder_path: Option<String>,
+
+    /// Reshare this http(s) URL instead of a local path, fetching it once on first
+    /// request; credentials may be given in the URL as user:pass@host
+    #[arg(long, value_name = "URL", conflicts_with_all = ["paths", "folder_path"])]
+    proxy: Option<String>,
+
+    /// Serve the files listed in this TOML manifest, each at its own route. Every
+    /// [[file]] entry has a path and a route, plus an optional download name and a
+    /// user and password that only that file accepts; entries without credentials
+    /// use --auth or --password-file when given and are public otherwise
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["paths", "folder_path", "proxy", "zip"])]
+    manifest: Option<PathBuf>,
 
     /// Port number
-    #[arg(short, long, default_value_t = 3000)]
+    #[arg(
+        short,
+        long,
+        visible_alias = "port",
+        env = "WFS_PORT",
+        default_value_t = 3000
+    )]
     port_number: u16,
 
-    /// Parallel downloads
-    #[arg(short = 'n', long, default_value_t = 10)]
-    parallel_downloads: u16,
-}
+    /// Use the first free port in this range instead, e.g. 3000-3100
+    #[arg(long, value_name = "FIRST-LAST", value_parser = parse_port_range, conflicts_with = "port_number")]
+    port_range: Option<(u16, u16)>,
 
-// ==========================
-// Application State
-// ==========================
-#[derive(Clone)]
-struct AppState {
-    host: String,
-    parallel_downloads: u16,
-    root: PathBuf,
-    download_cache: Arc<Mutex<LruCache<String, Vec<String>>>>,
-}
+    /// Interface address to listen on (all interfaces by default)
+    #[arg(short, long, env = "WFS_BIND")]
+    bind: Option<IpAddr>,
 
-#[derive(Deserialize)]
-struct SelectionRequest {
-    files: Vec<String>,
-    dirs: Vec<String>,
-}
+    /// Listen on all IPv6 interfaces and advertise the IPv6 address
+    #[arg(long, conflicts_with = "bind")]
+    ipv6: bool,
 
-#[derive(Serialize)]
-struct SelectionResponse {
-    id: String,
-}
+    /// Listen on all IPv4 and all IPv6 interfaces at once, on the same port, and
+    /// print a URL for each
+    #[arg(long, conflicts_with_all = ["bind", "ipv6"])]
+    dual_stack: bool,
 
-// ==========================
-// Entry Point
-// ==========================
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .init();
-
-    let args = Args::parse();
-
-    let root = PathBuf::from(&args.folder_path)
-        .canonicalize()
-        .expect("Invalid path");
-
-    let local_ip = local_ip_address::local_ip()
-        .map(|i| i.to_string())
-        .unwrap_or_else(|e| {
-            eprintln!(
-                "Warning: Could not determine local IP. Using 127.0.0.1. Error: {}",
-                e
-            );
-            "127.0.0.1".to_string()
-        });
-
-    let host = format!("http://{}:{}", local_ip, args.port_number);
-
-    let state = AppState {
-        host,
-        root,
-        parallel_downloads: args.parallel_downloads,
-        download_cache: Arc::new(Mutex::new(LruCache::new(
-            std::num::NonZeroUsize::new(100).unwrap(),
-        ))),
-    };
+    /// Advertise the address of this network interface, e.g. wlan0
+    #[arg(short, long, value_name = "NAME", conflicts_with = "bind")]
+    interface: Option<String>,
 
-    let app = Router::new()
-        .route("/", get(root_handler))
-        .route("/files/", get(root_handler))
-        .route("/files/{*path}", get(file_handler))
-        .route("/register-selection", post(register_selection))
-        .route("/config/{id}", get(config_handler))
-        .with_state(state);
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], args.port_number));
-
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .unwrap_or_else(|e| {
-            eprintln!(
-                "Error: Could not bind to port {}. Details: {}",
-                args.port_number, e
-            );
-            std::process::exit(1);
-        });
-
-    println!("Server running at http://{}:{}", local_ip, args.port_number);
-
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await
-    .unwrap();
-}
+    /// Listen on this Unix domain socket instead of TCP, e.g. behind a reverse proxy
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["bind", "ipv6", "dual_stack", "port_range", "interface", "tls"])]
+    unix: Option<PathBuf>,
 
-// ==========================
-// Route Handlers
-// ==========================
-async fn register_selection(
-    State(state): State<AppState>,
-    Json(payload): Json<SelectionRequest>,
-) -> impl IntoResponse {
-    let SelectionRequest { files, dirs } = payload;
+    /// Don't ask for confirmation before listening on all interfaces
+    #[arg(short, long)]
+    yes: bool,
 
-    // Expand directories server-side
-    let mut all_files = files;
-    all_files.extend(expand_dirs(&state.root, dirs).await);
+    /// Print the available network interfaces and their addresses, then exit
+    #[arg(long)]
+    list_interfaces: bool,
 
-    all_files.sort();
-    all_files.dedup();
+    /// Print a completion script for the given shell (bash, zsh, fish, ...), then exit
+    #[arg(long, value_name = "SHELL")]
+    generate_completions: Option<clap_complete::Shell>,
 
-    let id = Uuid::new_v4().to_string();
-    let mut cache = state.download_cache.lock().unwrap();
-    cache.put(id.clone(), all_files);
+    /// Parallel downloads
+    #[arg(short = 'n', long, default_value_t = 10)]
+    parallel_downloads: u16,
 
-    Json(SelectionResponse { id })
+    /// Let browsers preview text, images, media and PDFs instead of downloading them
+    #[arg(long)]
+    inline: bool,
+
+    /// Don't send nosniff, X-Frame-Options or Content-Security-Policy headers
+    #[arg(long)]
+    no_security_headers: bool,
+
+    /// Let web pages on other origins fetch the share (GET and HEAD only)
+    #[arg(long)]
+    cors: bool,
+
+    /// Only allow this origin under --cors (repeatable; implies --cors), e.g. https://example.com
+    #[arg(long, value_name = "ORIGIN")]
+    cors_origin: Vec<String>,
+
+    /// Cache-Control header sent with downloads, e.g. max-age=3600
+    #[arg(long, value_name = "VALUE", default_value = "no-cache")]
+    cache_control: String,
+
+    /// Only expose the top-level files of a shared folder; subfolders are hidden
+    /// and requests reaching into them get 400
+    #[arg(long)]
+    basename_only: bool,
+
+    /// Shell to write the suggested download command for; by default the banner
+    /// matches this machine and the landing page each visitor's platform
+    #[arg(long, value_name = "SHELL", value_enum)]
+    hint: Option<HintShell>,
+
+    /// Don't print a QR code of the server URL at startup
+    #[arg(long)]
+    no_qr: bool,
+
+    /// Also save the server URL as a QR code PNG image at this path
+    #[arg(long, value_name = "PATH")]
+    qr_png: Option<PathBuf>,
+
+    /// Open the server's front page in the default browser once it is listening
+    #[arg(long)]
+    open: bool,
+
+    /// Name advertised over mDNS, reachable as <HOSTNAME>.local on the network
+    #[arg(long, default_value = "wifi-file-server", value_parser = parse_hostname)]
+    hostname: String,
+
+    /// Require HTTP basic auth credentials, given as user:pass
+    #[arg(
+        long,
+        value_name = "USER:PASS",
+        value_parser = parse_credentials,
+        env = "WFS_AUTH",
+        hide_env_values = true
+    )]
+    auth: Option<Credentials>,
+
+    /// Require HTTP basic auth against the user:password lines of this file, which
+    /// keeps passwords out of shell history; bcrypt hashes (htpasswd -B) are accepted
+    #[arg(long, value_name = "PATH")]
+    password_file: Option<PathBuf>,
+
+    /// Only serve under /<TOKEN>/, so just people with the link get in;
+    /// a random token is generated when none is given
+    #[arg(long, value_name = "TOKEN", num_args = 0..=1, default_missing_value = "", value_parser = parse_token)]
+    token: Option<String>,
+
+    /// Cap each download at this many bytes per second, e.g. 512k or 2M
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Send the first SIZE of each download at full speed before --limit-rate
+    /// applies, so small files and previews stay instant, e.g. 256k
+    #[arg(long, value_name = "SIZE", default_value = "0", value_parser = parse_size, requires = "limit_rate")]
+    throttle_after: u64,
+
+    /// Read and send files in chunks of this size; larger chunks mean fewer
+    /// syscalls on fast links, e.g. 64k or 1M
+    #[arg(long, value_name = "SIZE", default_value = "4k", value_parser = parse_chunk_size)]
+    chunk_size: usize,
+
+    /// Ignore Range headers and always send whole files with 200, advertising
+    /// Accept-Ranges: none, so downloads can't be resumed or fetched in pieces
+    #[arg(long)]
+    no_ranges: bool,
+
+    /// Offer the shared folder as a single zip archive at /download.zip
+    #[arg(long)]
+    zip: bool,
+
+    /// Send a SHA-256 Digest header with full downloads (hashed in the background at startup or on first request)
+    #[arg(long)]
+    checksum: bool,
+
+    /// Don't hash files larger than this automatically under --checksum, e.g. 4G
+    #[arg(long, value_name = "SIZE", default_value = "1G", value_parser = parse_size)]
+    hash_threshold: u64,
+
+    /// Write a <FILE>.sha256 next to each shared file at startup, for sha256sum -c
+    #[arg(long)]
+    checksum_file: bool,
+
+    /// Watch the shared paths and drop cached checksums when files change
+    #[arg(long)]
+    watch: bool,
+
+    /// Accept multipart uploads at POST /upload, saving them into this folder
+    #[arg(long, value_name = "DIR")]
+    upload: Option<PathBuf>,
+
+    /// Reject uploads larger than this, e.g. 500M
+    #[arg(long, value_name = "SIZE", value_parser = parse_size, requires = "upload")]
+    max_upload_size: Option<u64>,
+
+    /// Also accept tus resumable uploads at /uploads, so big uploads over flaky
+    /// wifi pick up where they stopped
+    #[arg(long, requires = "upload")]
+    resumable: bool,
+
+    /// Refuse uploads whose name is taken with 409, instead of saving them as NAME-1.EXT
+    #[arg(long, requires = "upload", conflicts_with = "replace_on_upload")]
+    no_overwrite: bool,
+
+    /// Overwrite an existing file when an upload has the same name
+    #[arg(long, requires = "upload")]
+    replace_on_upload: bool,
+
+    /// Stop serving after this long, e.g. 90s, 10m or 2h
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    timeout: Option<Duration>,
+
+    /// Print uptime, downloads and bytes served every so often, e.g. 10m.
+    /// Silent under --quiet
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    status_interval: Option<Duration>,
+
+    /// Abort a download when the client has read nothing for this long, e.g. 30s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    stall_timeout: Option<Duration>,
+
+    /// Keep downloads open and stream bytes appended to the file as they are
+    /// written, like tail -f, e.g. to watch a build log
+    #[arg(long)]
+    follow: bool,
+
+    /// Under --follow, end a download once the file has not grown for this long, e.g. 5m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "follow")]
+    follow_timeout: Option<Duration>,
+
+    /// Exit after this many completed downloads
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_downloads: Option<u64>,
+
+    /// Delete the shared files once --max-downloads or --timeout stops the server
+    /// (not on Ctrl-C; folders are left alone)
+    #[arg(long, requires = "stop_condition")]
+    max_age_delete: bool,
+
+    /// POST a JSON summary (file, client, bytes, timestamp) of each completed
+    /// download to this http(s) URL; failures are logged and never affect the transfer
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    /// Serve at most this many downloads at once; extra requests get 503
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    max_connections: Option<u32>,
+
+    /// Allow each client IP at most N requests per minute; extra requests get 429
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit: Option<u32>,
+
+    /// Only serve clients in this network, e.g. 192.168.1.0/24 or a single
+    /// address (repeatable; all clients are served when omitted)
+    #[arg(long, value_name = "CIDR", value_parser = parse_network)]
+    allow: Vec<IpNet>,
+
+    /// Serve over HTTPS, generating a self-signed certificate unless --cert/--key are given
+    #[arg(long)]
+    tls: bool,
+
+    /// PEM certificate chain to use with --tls
+    #[arg(long, requires_all = ["tls", "key"])]
+    cert: Option<PathBuf>,
+
+    /// PEM private key to use with --tls
+    #[arg(long, requires_all = ["tls", "cert"])]
+    key: Option<PathBuf>,
+
+    /// Require every client to present a TLS certificate signed by a CA in this
+    /// PEM file (mutual TLS); connections without one are refused
+    #[arg(long, value_name = "PEM", requires = "tls")]
+    client_ca: Option<PathBuf>,
+
+    /// Increase log verbosity (-v for debug, -vv for trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Log output format; json emits one object per line for log pipelines
+    #[arg(long, value_name = "FORMAT", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Print nothing but errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Print only the share's URL, on a single line, instead of the startup banner
+    #[arg(long)]
+    print_url: bool,
+
+    /// Run the startup checks and print the URLs and settings, then exit without serving
+    #[arg(long)]
+    dry_run: bool,
 }
 
-async fn config_handler(
-    State(state): State<AppState>,
-    AxumPath(id): AxumPath<String>,
-) -> impl IntoResponse {
-    let files = {
-        let mut cache = state.download_cache.lock().unwrap();
-        cache.get(&id).cloned()
-    };
-
-    if let Some(file_list) = files {
-        let mut config = format!(
-            "globoff\ncontinue-at = -\nparallel\nparallel-max = {}\nparallel-immediate\nprogress-meter\n",
-            state.parallel_downloads
-        );
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-        for path in file_list {
-            let escaped = escape_curl_config_value(&path);
-            config.push_str(&format!(
-                "url = \"{}/files/{}\"\noutput = \"{}\"\n\n",
-                state.host, escaped, escaped
-            ));
-        }
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum HintShell {
+    Curl,
+    Powershell,
+}
 
-        (
-            StatusCode::OK,
-            [(header::CONTENT_TYPE, "text/plain")],
-            config,
-        )
-            .into_response()
+fn parse_file_name(value: &str) -> Result<String, String> {
+    if !value.is_empty() && Path::new(value).file_name() == Some(value.as_ref()) {
+        Ok(value.to_string())
     } else {
-        StatusCode::NOT_FOUND.into_response()
+        Err("expected a plain file name without path separators".to_string())
     }
 }
 
-async fn root_handler(
-    State(state): State<AppState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
-    if state.root.is_file() {
-        serve_file(&state.root, HeaderMap::new(), addr).await
+fn parse_hostname(value: &str) -> Result<String, String> {
+    let valid = !value.is_empty()
+        && value.len() <= 63
+        && !value.starts_with('-')
+        && !value.ends_with('-')
+        && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if valid {
+        Ok(value.to_ascii_lowercase())
     } else {
-        render_directory(&state.root, "").await.into_response()
+        Err("expected a DNS label of letters, digits and hyphens".to_string())
     }
 }
 
-async fn file_handler(
-    State(state): State<AppState>,
-    AxumPath(path): AxumPath<String>,
-    headers: HeaderMap,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-) -> impl IntoResponse {
-    let full_path = state.root.join(sanitize_path(&path));
-
-    if !full_path.exists() {
-        return StatusCode::NOT_FOUND.into_response();
-    }
-
-    if full_path.is_dir() {
-        render_directory(&full_path, &path).await.into_response()
+fn parse_token(value: &str) -> Result<String, String> {
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(value.to_string())
     } else {
-        serve_file(&full_path, headers, addr).await
+        Err("expected letters, digits, '-' or '_'".to_string())
     }
 }
 
-// ==========================
-// Utilities
-// ==========================
-fn sanitize_path(path: &str) -> PathBuf {
-    Path::new(path)
-        .components()
-        .filter(|c| matches!(c, Component::Normal(_)))
-        .collect()
-}
-
-pub fn escape_curl_config_value(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
-    for ch in input.chars() {
-        match ch {
-            '\\' => out.push_str("\\\\"),
-            '"' => out.push_str("\\\""),
-            ',' => out.push_str("\\,"),
-            '\n' => out.push_str("\\n"),
-            '\r' => out.push_str("\\r"),
-            '\t' => out.push_str("\\t"),
-            _ => out.push(ch),
-        }
+fn parse_port_range(value: &str) -> Result<(u16, u16), String> {
+    let invalid = || "expected a port range such as 3000-3100".to_string();
+    let (first, last) = value.split_once('-').ok_or_else(invalid)?;
+    let first: u16 = first.trim().parse().map_err(|_| invalid())?;
+    let last: u16 = last.trim().parse().map_err(|_| invalid())?;
+    if first > last {
+        return Err(format!("range start {} is above its end {}", first, last));
     }
-    out
+    Ok((first, last))
 }
 
-// ==========================
-// Directory Rendering
-// ==========================
-pub async fn render_directory(dir: &Path, base: &str) -> impl IntoResponse {
-    let mut entries = tokio::fs::read_dir(dir)
-        .await
-        .expect("Failed to read directory");
-    let mut items: Vec<DirItem> = Vec::new();
-
-    while let Ok(Some(entry)) = entries.next_entry().await {
-        let name = entry.file_name().to_string_lossy().to_string();
-        let is_dir = entry.file_type().await.is_ok_and(|ft| ft.is_dir());
-        let path = if base.is_empty() {
-            name.clone()
-        } else {
-            format!("{}/{}", base, &name)
-        };
-        let link_path = format!("/files/{}", path);
-
-        items.push(DirItem {
-            name,
-            path,
-            link_path,
-            is_dir,
-        });
-    }
+/// Parses a CIDR network; a bare address is taken as a network of one.
+fn parse_network(value: &str) -> Result<IpNet, String> {
+    value
+        .parse::<IpNet>()
+        .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_| "expected a network such as 192.168.1.0/24 or an IP address".to_string())
+}
 
-    items.sort_by(|a, b| UniCase::new(&a.name).cmp(&UniCase::new(&b.name)));
-
-    // Parent directory info
-    let parent = if !base.is_empty() {
-        Some(
-            Path::new(base)
-                .parent()
-                .unwrap_or(Path::new(""))
-                .to_string_lossy()
-                .to_string(),
-        )
-    } else {
-        None
+/// Parses a byte count with an optional binary suffix (`k`, `M`, `G`), so
+/// `512k` is 524288 bytes.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier: u64 = match value[digits.len()..].to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        suffix => return Err(format!("unknown size suffix '{}'", suffix)),
     };
-
-    Html(generate_page_html_template(parent, items))
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid size", value))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("'{}' is too large", value))
 }
 
-struct DirItem {
-    name: String,
-    path: String,
-    link_path: String,
-    is_dir: bool,
+fn parse_rate(value: &str) -> Result<u64, String> {
+    match parse_size(value)? {
+        0 => Err("rate must be greater than zero".to_string()),
+        rate => Ok(rate),
+    }
 }
 
-impl DirItem {
-    fn to_html(&self) -> String {
-        let icon = if self.is_dir { "📁" } else { "📄" };
-        format!(
-            r#"<div class="item-row">
-                <div class="checkbox-wrapper">
-                    <input type="checkbox" class="item-check" data-path="{path}" data-is-dir="{is_dir}">
-                </div>
-                <span class="icon">{icon}</span>
-                <a class="file-link" href="{link_path}">{name}</a>
-            </div>"#,
-            path = self.path,
-            is_dir = self.is_dir,
-            icon = icon,
-            link_path = self.link_path,
-            name = self.name
-        )
+fn parse_chunk_size(value: &str) -> Result<usize, String> {
+    match parse_size(value)? {
+        0 => Err("chunk size must be greater than zero".to_string()),
+        size if size > 64 * 1024 * 1024 => Err("chunk size must be at most 64M".to_string()),
+        size => Ok(size as usize),
     }
 }
 
-fn generate_page_html_template(parent: Option<String>, items: Vec<DirItem>) -> String {
-    let mut content = String::new();
-
-    // Parent directory row
-    if let Some(parent_path) = parent {
-        content.push_str(&format!(
-            r#"<div class="item-row parent-row">
-                <a href="/files/{}" style="text-decoration:none; color:#666;">⤴ .. (Parent Directory)</a>
-            </div>"#,
-            parent_path
-        ));
+/// Parses a duration with an `s`, `m` or `h` suffix; bare numbers are seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let unit_secs: u64 = match &value[digits.len()..] {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        suffix => return Err(format!("unknown duration suffix '{}'", suffix)),
+    };
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid duration", value))?;
+    match number.checked_mul(unit_secs) {
+        Some(0) => Err("duration must be greater than zero".to_string()),
+        Some(secs) => Ok(Duration::from_secs(secs)),
+        None => Err(format!("'{}' is too long", value)),
     }
+}
 
-    // File and folder rows
-    for item in items {
-        content.push_str(&item.to_html());
+/// Arguments that can also be set through the environment, by argument id.
+const ENV_VARS: [(&str, &str); 4] = [
+    ("folder_path", "WFS_FILE"),
+    ("port_number", "WFS_PORT"),
+    ("bind", "WFS_BIND"),
+    ("auth", "WFS_AUTH"),
+];
+
+fn exit_on_args_error(e: clap::Error) -> ! {
+    // Help and version requests are not errors; everything else exits with 1
+    if !e.use_stderr() {
+        e.exit();
     }
+    let _ = e.print();
+    std::process::exit(1);
+}
 
-    // Inject into HTML template
-    let html_template = include_str!("../index.html");
-    html_template.replace("{{CONTENT}}", &content)
+fn parse_credentials(value: &str) -> Result<Credentials, String> {
+    match value.split_once(':') {
+        Some((user, pass)) if !user.is_empty() => Ok(Credentials {
+            user: user.to_string(),
+            pass: pass.to_string(),
+        }),
+        _ => Err("expected credentials in the form user:pass".to_string()),
+    }
 }
 
 // ==========================
-// File Serving
+// Entry Point
 // ==========================
-async fn serve_file(path: &Path, headers: HeaderMap, client: SocketAddr) -> Response {
-    let mut file = match File::open(path).await {
-        Ok(f) => f,
-        Err(_) => return StatusCode::NOT_FOUND.into_response(),
-    };
+#[tokio::main]
+async fn main() {
+    let matches = Args::command()
+        .try_get_matches()
+        .unwrap_or_else(|e| exit_on_args_error(e));
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| exit_on_args_error(e));
+
+    if let Some(shell) = args.generate_completions {
+        clap_complete::generate(shell, &mut Args::command(), "wfpush", &mut io::stdout());
+        return;
+    }
+
+    // Precedence is command line, then environment, then built-in defaults
+    let from_env: Vec<&str> = ENV_VARS
+        .iter()
+        .filter(|(id, _)| matches.value_source(id) == Some(ValueSource::EnvVariable))
+        .map(|(_, var)| *var)
+        .collect();
 
-    let metadata = match file.metadata().await {
-        Ok(m) => m,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    let level = match args.verbose {
+        _ if args.quiet => tracing::Level::ERROR,
+        0 => tracing::Level::INFO,
+        1 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
     };
+    let logs = tracing_subscriber::fmt().with_max_level(level);
+    match args.log_format {
+        LogFormat::Text => logs.init(),
+        // Event fields sit at the top level so each request is one flat object
+        LogFormat::Json => logs.json().flatten_event(true).init(),
+    }
 
-    let file_size = metadata.len();
-    let filename = path.file_name().unwrap().to_string_lossy();
-    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    if args.list_interfaces {
+        match local_ip_address::list_afinet_netifas() {
+            Ok(interfaces) => {
+                for (name, ip) in interfaces {
+                    println!("{}\t{}", name, ip);
+                }
+            }
+            Err(e) => {
+                eprintln!("Error: Could not list network interfaces. Details: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let mut response_headers = HeaderMap::new();
-    response_headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
-    response_headers.insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_str(mime.as_ref()).unwrap(),
-    );
-    response_headers.insert(
-        header::CONTENT_DISPOSITION,
-        HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename)).unwrap(),
-    );
+    #[cfg(unix)]
+    let unix = args.unix.is_some();
+    #[cfg(not(unix))]
+    let unix = false;
+    let all_interfaces = args.bind.is_none() && !unix;
+    if all_interfaces
+        && !args.yes
+        && !args.dry_run
+        && io::stdin().is_terminal()
+        && !confirm_public()
+    {
+        println!("Not serving.");
+        return;
+    }
 
-    if let Some(range_header) = headers.get(RANGE) {
-        if let Some((start, end)) = parse_range(range_header.to_str().unwrap_or(""), file_size) {
-            info!(
-                client = %client.ip(),
-                file = %path.display(),
-                range = %format!("{}-{}", start, end),
-                "File download (partial)"
-            );
-
-            let length = end - start + 1;
-            file.seek(SeekFrom::Start(start)).await.ok();
-
-            let stream = ReaderStream::new(file.take(length));
-            let body = Body::from_stream(stream);
-
-            response_headers.insert(
-                header::CONTENT_RANGE,
-                HeaderValue::from_str(&format!("bytes {}-{}/{}", start, end, file_size)).unwrap(),
-            );
-            response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
-
-            let mut res = Response::new(body);
-            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
-            *res.headers_mut() = response_headers;
-            return res;
+    let mut paths: Vec<PathBuf> = args.paths.iter().map(PathBuf::from).collect();
+    paths.extend(args.folder_path.as_ref().map(PathBuf::from));
+    // Removed on shutdown, like the `--proxy` cache
+    let mut buffered = Vec::new();
+    for path in paths.iter_mut() {
+        if path.as_os_str() == "-" {
+            *path = buffer_stdin(&args.stdin_name, args.quiet || args.print_url)
+                .await
+                .unwrap_or_else(|e| {
+                    eprintln!("Error: Could not buffer stdin. Details: {}", e);
+                    std::process::exit(1);
+                });
+            buffered.extend(path.parent().map(PathBuf::from));
         }
     }
 
-    info!(
-        client = %client.ip(),
-        file = %path.display(),
-        size = file_size,
-        "File download (full)"
-    );
-
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-    response_headers.insert(header::CONTENT_LENGTH, HeaderValue::from(file_size));
+    if !from_env.is_empty() && !args.quiet {
+        println!(
+            "Using {} from the environment (command-line flags override environment variables, which override defaults)",
+            from_env.join(", ")
+        );
+    }
 
-    let mut res = Response::new(body);
-    *res.headers_mut() = response_headers;
-    res
-}
+    let config = ServerConfig {
+        paths,
+        manifest: args.manifest,
+        allow_special: args.allow_special,
+        serve_symlinks: args.serve_symlinks,
+        route: args.route,
+        download_name: args.name,
+        content_type: args.content_type,
+        port: args.port_number,
+        port_range: args.port_range,
+        bind: args.bind,
+        ipv6: args.ipv6,
+        dual_stack: args.dual_stack,
+        interface: args.interface,
+        #[cfg(unix)]
+        unix: args.unix,
+        parallel_downloads: args.parallel_downloads,
+        inline: args.inline,
+        hint: args.hint.map(|shell| match shell {
+            HintShell::Curl => DownloadHint::Curl,
+            HintShell::Powershell => DownloadHint::PowerShell,
+        }),
+        qr: !args.no_qr,
+        qr_png: args.qr_png,
+        open: args.open,
+        hostname: args.hostname,
+        auth: args.auth,
+        password_file: args.password_file,
+        // A bare --token asks for a fresh random secret
+        token: args.token.map(|token| {
+            if token.is_empty() {
+                Uuid::new_v4().simple().to_string()
+            } else {
+                token
+            }
+        }),
+        limit_rate: args.limit_rate,
+        throttle_after: args.throttle_after,
+        chunk_size: args.chunk_size,
+        no_ranges: args.no_ranges,
+        zip: args.zip,
+        checksum: args.checksum,
+        hash_threshold: args.hash_threshold,
+        checksum_file: args.checksum_file,
+        basename_only: args.basename_only,
+        security_headers: !args.no_security_headers,
+        cors: args.cors || !args.cors_origin.is_empty(),
+        cors_origins: args.cors_origin,
+        cache_control: args.cache_control,
+        watch: args.watch,
+        upload: args.upload,
+        max_upload_size: args.max_upload_size,
+        resumable: args.resumable,
+        upload_conflict: if args.no_overwrite {
+            UploadConflict::Refuse
+        } else if args.replace_on_upload {
+            UploadConflict::Replace
+        } else {
+            UploadConflict::Rename
+        },
+        timeout: args.timeout,
+        max_downloads: args.max_downloads,
+        delete_when_done: args.max_age_delete,
+        webhook: args.webhook,
+        max_connections: args.max_connections,
+        rate_limit: args.rate_limit,
+        allow: args.allow,
+        proxy: args.proxy,
+        quiet: args.quiet,
+        print_url: args.print_url,
+        status_interval: args.status_interval,
+        stall_timeout: args.stall_timeout,
+        follow: args.follow,
+        follow_timeout: args.follow_timeout,
+        dry_run: args.dry_run,
+        tls: args.tls,
+        cert: args.cert,
+        key: args.key,
+        client_ca: args.client_ca,
+    };
 
-// ==========================
-// Range Parsing
-// ==========================
-fn parse_range(header: &str, size: u64) -> Option<(u64, u64)> {
-    if !header.starts_with("bytes=") {
-        return None;
+    let served = wifi_file_server::run(config).await;
+    for dir in &buffered {
+        let _ = fs::remove_dir_all(dir).await;
     }
-    let mut parts = header.trim_start_matches("bytes=").split('-');
-    let start = parts.next()?.parse::<u64>().ok()?;
-    let end = parts.next()?.parse::<u64>().unwrap_or(size - 1);
-    if start <= end && end < size {
-        Some((start, end))
-    } else {
-        None
+    if let Err(e) = served {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
     }
 }
 
-// ==========================
-// Directory Expansion
-// ==========================
-async fn expand_dirs(root: &Path, dirs: Vec<String>) -> Vec<String> {
-    let mut result = Vec::new();
-    let mut stack: Vec<PathBuf> = dirs
-        .into_iter()
-        .map(|d| root.join(sanitize_path(&d)))
-        .collect();
+/// Asks before exposing the share to the whole network; anything but y/yes declines.
+fn confirm_public() -> bool {
+    eprint!(
+        "Warning: the share will be reachable by everyone on your network (pass --bind to limit this, or --yes to skip this question).\nContinue? [y/N] "
+    );
+    let _ = io::stderr().flush();
 
-    while let Some(path) = stack.pop() {
-        let mut rd = match fs::read_dir(&path).await {
-            Ok(r) => r,
-            Err(_) => continue,
-        };
-        while let Ok(Some(entry)) = rd.next_entry().await {
-            let p = entry.path();
-            if entry.file_type().await.map(|f| f.is_dir()).unwrap_or(false) {
-                stack.push(p);
-            } else if let Ok(rel) = p.strip_prefix(root) {
-                result.push(rel.to_string_lossy().to_string());
-            }
-        }
+    let mut answer = String::new();
+    if io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
     }
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
 
-    result.sort();
-    result
+/// Copies stdin into a fresh temporary directory so it can be served (and
+/// seeked for range requests) like any other file.
+async fn buffer_stdin(name: &str, quiet: bool) -> io::Result<PathBuf> {
+    let dir = env::temp_dir().join(format!("wfpush-{}", Uuid::new_v4()));
+    fs::create_dir_all(&dir).await?;
+
+    let path = dir.join(name);
+    let mut file = File::create(&path).await?;
+    let bytes = tokio::io::copy(&mut tokio::io::stdin(), &mut file).await?;
+    file.flush().await?;
+
+    if !quiet {
+        println!(
+            "Buffered {} from stdin to {}",
+            format_size(bytes),
+            path.display()
+        );
+    }
+    Ok(path)
 }
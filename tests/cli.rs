@@ -0,0 +1,288 @@
+//! Runs the `wfpush` binary itself, for behaviour that lives outside the router.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    process::{Command, Stdio},
+};
+
+#[cfg(unix)]
+#[test]
+fn sigterm_shuts_down_gracefully() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("notes.txt");
+    std::fs::write(&file, "hello\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg(&file)
+        .args(["--bind", "127.0.0.1", "--port", "0", "--print-url"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // The URL is printed once the listener is bound
+    let mut url = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut url)
+        .unwrap();
+    assert!(url.starts_with("http://127.0.0.1:"), "{}", url);
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert!(child.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn stdin_is_buffered_quietly_and_cleaned_up() {
+    let tmp = tempfile::tempdir().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg("-")
+        .args(["--bind", "127.0.0.1", "--port", "0", "--print-url"])
+        .env("TMPDIR", tmp.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(b"hello\n").unwrap();
+
+    // Under --print-url the URL is the only thing printed
+    let mut url = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut url)
+        .unwrap();
+    assert!(url.starts_with("http://127.0.0.1:"), "{}", url);
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+    assert_eq!(std::fs::read_dir(tmp.path()).unwrap().count(), 0);
+}
+
+/// Sends a bare GET and returns the status line.
+fn status_line(addr: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"GET /download HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+#[cfg(unix)]
+#[test]
+fn dual_stack_serves_both_families() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("notes.txt");
+    std::fs::write(&file, "hello\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg(&file)
+        .args(["--dual-stack", "--port", "0", "--print-url"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut url = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut url)
+        .unwrap();
+    let port = url
+        .trim()
+        .trim_end_matches("/download")
+        .rsplit(':')
+        .next()
+        .unwrap()
+        .to_string();
+
+    assert_eq!(
+        status_line(&format!("127.0.0.1:{}", port)),
+        "HTTP/1.1 200 OK"
+    );
+    assert_eq!(status_line(&format!("[::1]:{}", port)), "HTTP/1.1 200 OK");
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn taken_port_is_reported_not_panicked() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("notes.txt");
+    std::fs::write(&file, "hello\n").unwrap();
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port().to_string();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg(&file)
+        .args(["--bind", "127.0.0.1", "--port", &port, "--no-qr"])
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("Could not bind to 127.0.0.1:{}", port)),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("--port-range"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn status_interval_prints_a_summary_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("notes.txt");
+    std::fs::write(&file, "hello\n").unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg(&file)
+        .args(["--bind", "127.0.0.1", "--port", "0", "--print-url"])
+        .args(["--status-interval", "1s"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut url = String::new();
+    stdout.read_line(&mut url).unwrap();
+    let addr = url
+        .trim()
+        .trim_start_matches("http://")
+        .trim_end_matches("/download")
+        .to_string();
+    assert_eq!(status_line(&addr), "HTTP/1.1 200 OK");
+
+    // Request logs share stdout with the summary
+    let status = stdout
+        .lines()
+        .map(Result::unwrap)
+        .find(|line| line.starts_with("Status:"))
+        .unwrap();
+    assert!(
+        status.ends_with("s, 1 downloads completed, 0 active, 6 B served"),
+        "{}",
+        status
+    );
+
+    let killed = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    assert!(child.wait().unwrap().success());
+}
+
+// Windows resolves `notes.txt\..` lexically, to the folder
+#[cfg(unix)]
+#[test]
+fn pathological_paths_exit_with_an_error_instead_of_panicking() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("notes.txt");
+    std::fs::write(&file, "hello\n").unwrap();
+
+    for path in [
+        file.join(".."),
+        dir.path().join("missing").join(".."),
+        "".into(),
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+            .arg(&path)
+            .arg("--dry-run")
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(1), "{:?}: {}", path, stderr);
+        assert!(stderr.starts_with("Error: "), "{:?}: {}", path, stderr);
+        assert!(!stderr.contains("panicked"), "{:?}: {}", path, stderr);
+    }
+}
+
+#[test]
+fn chunk_size_must_be_between_one_byte_and_64m() {
+    for size in ["0", "65M", "lots"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+            .args(["--chunk-size", size, "--dry-run"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1), "{}", size);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--chunk-size"));
+    }
+}
+
+#[test]
+fn help_and_version_are_generated() {
+    let help = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg("--help")
+        .output()
+        .unwrap();
+    assert!(help.status.success());
+    let help = String::from_utf8_lossy(&help.stdout);
+    assert!(
+        help.starts_with("Usage: wfpush [OPTIONS] [PATHS]..."),
+        "{}",
+        help
+    );
+    assert!(help.contains("--port"), "{}", help);
+
+    let version = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    assert!(version.status.success());
+    assert!(
+        String::from_utf8_lossy(&version.stdout)
+            .trim()
+            .ends_with(env!("CARGO_PKG_VERSION"))
+    );
+}
+
+#[test]
+fn a_bare_file_argument_shares_it_with_the_defaults() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("notes.txt");
+    std::fs::write(&file, "hello\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_wfpush"))
+        .arg(&file)
+        .arg("--dry-run")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let shared = file.canonicalize().unwrap();
+    assert!(
+        stdout.contains(&format!("Sharing file: {}", shared.display())),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout
+            .lines()
+            .any(|line| line.starts_with("Download URL: http://")
+                && line.ends_with(":3000/download")),
+        "{}",
+        stdout
+    );
+}
@@ -0,0 +1,46 @@
+//! The filename rules shared by uploads, `--name`, manifests and downloads.
+
+use wifi_file_server::sanitize_filename;
+
+#[test]
+fn only_the_last_component_is_kept() {
+    assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+    assert_eq!(sanitize_filename(r"..\..\Windows\win.ini"), "win.ini");
+    assert_eq!(sanitize_filename(r"C:\Users\me/report.pdf"), "report.pdf");
+    assert_eq!(sanitize_filename("notes/"), "");
+}
+
+#[test]
+fn leading_and_trailing_dots_are_stripped() {
+    assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+    assert_eq!(sanitize_filename(".."), "");
+    assert_eq!(sanitize_filename(". . ."), "");
+    assert_eq!(sanitize_filename("report.pdf. "), "report.pdf");
+}
+
+#[test]
+fn control_and_forbidden_characters_are_removed() {
+    assert_eq!(sanitize_filename("a\nb\tc\u{7f}.txt"), "abc.txt");
+    assert_eq!(
+        sanitize_filename("what?<now>:\"|*.txt"),
+        "what__now_____.txt"
+    );
+}
+
+#[test]
+fn reserved_device_names_are_prefixed() {
+    for name in ["CON", "nul", "Aux.txt", "com1.tar.gz", "LPT9"] {
+        assert_eq!(sanitize_filename(name), format!("_{}", name));
+    }
+    assert_eq!(sanitize_filename("CONSOLE.txt"), "CONSOLE.txt");
+    assert_eq!(sanitize_filename("com10"), "com10");
+}
+
+#[test]
+fn sanitizing_twice_changes_nothing() {
+    for name in ["../../etc/passwd", ".. CON. ", "a:b?c", "ünïcode 名前.txt"] {
+        let once = sanitize_filename(name);
+        assert_eq!(sanitize_filename(&once), once);
+    }
+    assert_eq!(sanitize_filename("ünïcode 名前.txt"), "ünïcode 名前.txt");
+}
@@ -0,0 +1,1908 @@
+//! Boots the server on an ephemeral port and talks to it over real HTTP.
+
+use std::path::Path;
+
+use reqwest::{StatusCode, header};
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use wifi_file_server::{ServerConfig, UploadConflict, run_on};
+
+const FIXTURE: &[u8] = b"The quick brown fox jumps over the lazy dog\n";
+
+/// A running server and the temp folder holding what it shares. The server
+/// task ends with the test's runtime.
+struct TestServer {
+    base: String,
+    client: reqwest::Client,
+    dir: TempDir,
+}
+
+impl TestServer {
+    /// Shares a single fixture file named `notes.txt`.
+    async fn single_file() -> Self {
+        Self::single_file_with(|_, _| {}).await
+    }
+
+    /// Shares `notes.txt` like [`TestServer::single_file`], after `configure`
+    /// has adjusted the config or added files beside it.
+    async fn single_file_with(configure: impl FnOnce(&mut ServerConfig, &Path)) -> Self {
+        Self::sharing("notes.txt", FIXTURE, configure).await
+    }
+
+    /// Shares one file called `name` holding `contents`.
+    async fn sharing(
+        name: &str,
+        contents: &[u8],
+        configure: impl FnOnce(&mut ServerConfig, &Path),
+    ) -> Self {
+        Self::start(|config, dir| {
+            let file = dir.join(name);
+            std::fs::write(&file, contents).unwrap();
+            config.paths = vec![file];
+            configure(config, dir);
+        })
+        .await
+    }
+
+    /// Shares the temp folder holding a small, a large and a nested file.
+    async fn folder() -> Self {
+        Self::folder_with(|_, _| {}).await
+    }
+
+    /// Shares the folder of [`TestServer::folder`] after `configure` has run.
+    async fn folder_with(configure: impl FnOnce(&mut ServerConfig, &Path)) -> Self {
+        Self::start(|config, dir| {
+            std::fs::write(dir.join("b-small.txt"), "x").unwrap();
+            std::fs::write(dir.join("a-large.bin"), vec![0; 100]).unwrap();
+            std::fs::create_dir(dir.join("docs")).unwrap();
+            std::fs::write(dir.join("docs").join("c.txt"), FIXTURE).unwrap();
+            config.paths = vec![dir.to_path_buf()];
+            configure(config, dir);
+        })
+        .await
+    }
+
+    /// Starts a quiet server on a free port with whatever `setup` shares.
+    /// Under `--tls` the client accepts the self-signed certificate.
+    async fn start(setup: impl FnOnce(&mut ServerConfig, &Path)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig {
+            quiet: true,
+            qr: false,
+            ..ServerConfig::default()
+        };
+        setup(&mut config, dir.path());
+        let scheme = if config.tls { "https" } else { "http" };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { run_on(config, listener).await.unwrap() });
+
+        Self {
+            base: format!("{}://{}", scheme, addr),
+            client: reqwest::Client::builder()
+                .tls_danger_accept_invalid_certs(true)
+                .build()
+                .unwrap(),
+            dir,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    async fn get(&self, path: &str) -> reqwest::Response {
+        self.client.get(self.url(path)).send().await.unwrap()
+    }
+
+    async fn get_json(&self, path: &str) -> serde_json::Value {
+        let body = self.get(path).await.bytes().await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn download_serves_the_file() {
+    let server = TestServer::single_file().await;
+
+    let response = server.get("/download").await;
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let headers = response.headers();
+    assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+    assert_eq!(headers[header::CONTENT_LENGTH], FIXTURE.len().to_string());
+    assert_eq!(headers[header::ACCEPT_RANGES], "bytes");
+    assert_eq!(headers[header::CACHE_CONTROL], "no-cache");
+    assert!(headers.contains_key(header::ETAG));
+    assert!(headers.contains_key(header::LAST_MODIFIED));
+    let disposition = headers[header::CONTENT_DISPOSITION].to_str().unwrap();
+    assert!(disposition.starts_with("attachment"), "{}", disposition);
+    assert!(disposition.contains("notes.txt"), "{}", disposition);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+}
+
+#[tokio::test]
+async fn head_sends_headers_without_a_body() {
+    let server = TestServer::single_file().await;
+
+    let response = server
+        .client
+        .head(server.url("/download"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_LENGTH],
+        FIXTURE.len().to_string()
+    );
+    assert!(response.bytes().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn range_requests_get_partial_content() {
+    let server = TestServer::single_file().await;
+
+    let response = server
+        .client
+        .get(server.url("/download"))
+        .header(header::RANGE, "bytes=4-8")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()[header::CONTENT_RANGE],
+        format!("bytes 4-8/{}", FIXTURE.len())
+    );
+    assert_eq!(response.bytes().await.unwrap(), &FIXTURE[4..=8]);
+}
+
+async fn ranged(server: &TestServer, range: &str) -> reqwest::Response {
+    server
+        .client
+        .get(server.url("/download"))
+        .header(header::RANGE, range)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn resumed_download_completes_the_file() {
+    let contents: &'static [u8] = b"abcdefg";
+    let server = TestServer::sharing("data.bin", contents, |_, _| {}).await;
+    let half = contents.len() / 2;
+
+    let first = ranged(&server, &format!("bytes=0-{}", half - 1)).await;
+    assert_eq!(first.status(), StatusCode::PARTIAL_CONTENT);
+    let mut downloaded = first.bytes().await.unwrap().to_vec();
+    assert_eq!(downloaded.len(), half);
+
+    let rest = ranged(&server, &format!("bytes={}-", half)).await;
+    assert_eq!(rest.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        rest.headers()[header::CONTENT_RANGE],
+        format!("bytes {}-{}/{}", half, contents.len() - 1, contents.len())
+    );
+    downloaded.extend_from_slice(&rest.bytes().await.unwrap());
+    assert_eq!(downloaded, contents);
+}
+
+#[tokio::test]
+async fn single_byte_files_have_one_byte_ranges() {
+    let server = TestServer::sharing("data.bin", b"x", |_, _| {}).await;
+
+    let response = ranged(&server, "bytes=0-").await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 0-0/1");
+    assert_eq!(response.bytes().await.unwrap(), "x");
+
+    // Nothing is left to resume past the only byte
+    let response = ranged(&server, "bytes=1-").await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */1");
+}
+
+#[tokio::test]
+async fn empty_files_have_no_satisfiable_range() {
+    let server = TestServer::sharing("data.bin", b"", |_, _| {}).await;
+
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = ranged(&server, "bytes=0-").await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */0");
+}
+
+#[tokio::test]
+async fn ranges_past_the_end_are_clamped() {
+    let server = TestServer::single_file().await;
+
+    let response = ranged(&server, "bytes=40-1000").await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(
+        response.headers()[header::CONTENT_RANGE],
+        format!("bytes 40-{}/{}", FIXTURE.len() - 1, FIXTURE.len())
+    );
+    assert_eq!(response.bytes().await.unwrap(), &FIXTURE[40..]);
+}
+
+#[tokio::test]
+async fn no_ranges_sends_the_whole_file() {
+    let server = TestServer::single_file_with(|config, _| config.no_ranges = true).await;
+
+    let response = server
+        .client
+        .get(server.url("/download"))
+        .header(header::RANGE, "bytes=4-8")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ACCEPT_RANGES], "none");
+    assert!(response.headers().get(header::CONTENT_RANGE).is_none());
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+}
+
+#[tokio::test]
+async fn matching_etag_is_not_modified() {
+    let server = TestServer::single_file().await;
+
+    let first = server.get("/download").await;
+    let etag = first.headers()[header::ETAG].clone();
+
+    let response = server
+        .client
+        .get(server.url("/download"))
+        .header(header::IF_NONE_MATCH, etag)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert!(response.bytes().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn checksum_matches_the_known_digest() {
+    let server = TestServer::single_file_with(|config, _| config.checksum = true).await;
+
+    // `printf 'The quick brown fox jumps over the lazy dog\n' | sha256sum`
+    let expected = "c03905fcdab297513a620ec81ed46ca44ddb62d41cbbd83eb4a5a3592be26a69";
+    let mut checksum = server.get("/checksum").await;
+    for _ in 0..100 {
+        if checksum.status() != StatusCode::SERVICE_UNAVAILABLE {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        checksum = server.get("/checksum").await;
+    }
+    assert_eq!(checksum.status(), StatusCode::OK);
+    assert_eq!(checksum.text().await.unwrap(), format!("{}\n", expected));
+
+    let response = server.get("/download").await;
+    assert_eq!(
+        response.headers()["digest"],
+        "sha-256=wDkF/Nqyl1E6Yg7IHtRspE3bYtQcu9g+tKWjWSviamk="
+    );
+}
+
+#[tokio::test]
+async fn content_disposition_carries_spaces_emoji_and_quotes() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.download_name = Some("Bob's \"final\" 🎉.txt".to_string());
+    })
+    .await;
+
+    let response = server.get("/download").await;
+    // `"` is never kept in a name; the rest survives in `filename*`
+    assert_eq!(
+        response.headers()[header::CONTENT_DISPOSITION],
+        "attachment; filename=\"Bob's _final_ _.txt\"; \
+         filename*=UTF-8''Bob%27s%20_final_%20%F0%9F%8E%89.txt"
+    );
+}
+
+#[tokio::test]
+async fn tiny_chunks_still_send_every_byte() {
+    let server = TestServer::single_file_with(|config, _| config.chunk_size = 5).await;
+
+    assert_eq!(
+        server.get("/download").await.bytes().await.unwrap(),
+        FIXTURE
+    );
+    let response = ranged(&server, "bytes=3-21").await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.bytes().await.unwrap(), &FIXTURE[3..=21]);
+}
+
+/// How many pieces a download of 64 KiB arrives in at `chunk_size`. Each
+/// read becomes its own HTTP/2 DATA frame, which TCP cannot merge the way
+/// it merges HTTP/1.1 writes, so the count follows the reads.
+async fn download_pieces(chunk_size: usize) -> usize {
+    let server = TestServer::sharing("big.bin", &[b'x'; 64 * 1024], |config, _| {
+        config.tls = true;
+        config.chunk_size = chunk_size;
+    })
+    .await;
+
+    let mut response = server.get("/download").await;
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    let (mut pieces, mut bytes) = (0, 0);
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        pieces += 1;
+        bytes += chunk.len();
+    }
+    assert_eq!(bytes, 64 * 1024);
+    pieces
+}
+
+#[tokio::test]
+async fn larger_chunks_send_fewer_frames() {
+    let small = download_pieces(1024).await;
+    let large = download_pieces(64 * 1024).await;
+    assert!(small >= 64, "{}", small);
+    assert!(large * 4 <= small, "{} vs {}", large, small);
+}
+
+// A newline in a file name once went straight into a header and tripped an unwrap
+#[cfg(unix)]
+#[tokio::test]
+async fn control_characters_in_names_never_break_headers() {
+    let server = TestServer::sharing("line\nbreak.txt", FIXTURE, |_, _| {}).await;
+
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_DISPOSITION],
+        "attachment; filename=\"linebreak.txt\"; filename*=UTF-8''linebreak.txt"
+    );
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+    assert_eq!(server.get("/download").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn last_modified_is_the_file_mtime_as_an_http_date() {
+    let server = TestServer::single_file().await;
+
+    let response = server.get("/download").await;
+    let header = response.headers()[header::LAST_MODIFIED].to_str().unwrap();
+    let modified = httpdate::parse_http_date(header).unwrap();
+    let on_disk = std::fs::metadata(server.dir.path().join("notes.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    // HTTP dates only carry whole seconds
+    let drift = on_disk.duration_since(modified).unwrap();
+    assert!(drift < std::time::Duration::from_secs(1), "{:?}", drift);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unreadable_files_are_forbidden() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = TestServer::folder_with(|_, dir| {
+        let secret = dir.join("secret.txt");
+        std::fs::write(&secret, FIXTURE).unwrap();
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o000)).unwrap();
+    })
+    .await;
+    // Root reads everything regardless of the mode, so there is nothing to test
+    if std::fs::File::open(server.dir.path().join("secret.txt")).is_ok() {
+        return;
+    }
+
+    assert_eq!(
+        server.get("/files/secret.txt").await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        server.get("/files/gone.txt").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn unreadable_files_are_left_out_of_archives() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let server = TestServer::folder_with(|config, dir| {
+        config.zip = true;
+        let secret = dir.join("secret.txt");
+        std::fs::write(&secret, FIXTURE).unwrap();
+        std::fs::set_permissions(&secret, std::fs::Permissions::from_mode(0o000)).unwrap();
+    })
+    .await;
+    // Root reads everything regardless of the mode, so there is nothing to test
+    if std::fs::File::open(server.dir.path().join("secret.txt")).is_ok() {
+        return;
+    }
+
+    let response = server.get("/download.zip").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let archive = response.bytes().await.unwrap();
+    let contains = |name: &[u8]| archive.windows(name.len()).any(|w| w == name);
+    assert!(contains(b"docs/c.txt"));
+    assert!(!contains(b"secret.txt"));
+    // The end of central directory record, which a truncated archive lacks
+    assert!(contains(b"PK\x05\x06"));
+}
+
+#[tokio::test]
+async fn cache_control_is_the_configured_value() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.cache_control = "max-age=3600".to_string();
+    })
+    .await;
+
+    let response = server.get("/download").await;
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=3600");
+    let etag = response.headers()[header::ETAG].clone();
+    let cached = server
+        .client
+        .get(server.url("/download"))
+        .header(header::IF_NONE_MATCH, etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(cached.headers()[header::CACHE_CONTROL], "max-age=3600");
+}
+
+#[cfg(windows)]
+#[tokio::test]
+async fn backslash_paths_give_the_bare_file_name() {
+    let server = TestServer::start(|config, dir| {
+        std::fs::create_dir(dir.join("Users")).unwrap();
+        std::fs::write(dir.join("Users").join("report.pdf"), FIXTURE).unwrap();
+        let path = format!(r"{}\Users\report.pdf", dir.display());
+        config.paths = vec![std::path::PathBuf::from(path)];
+    })
+    .await;
+
+    let response = server.get("/download").await;
+    assert_eq!(
+        response.headers()[header::CONTENT_DISPOSITION],
+        "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+    );
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+}
+
+#[tokio::test]
+async fn other_methods_get_405_with_allow() {
+    let server = TestServer::single_file().await;
+
+    let response = server
+        .client
+        .post(server.url("/download"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let allow = response.headers()[header::ALLOW].to_str().unwrap();
+    assert!(allow.contains("GET"), "{}", allow);
+    assert!(allow.contains("HEAD"), "{}", allow);
+}
+
+#[tokio::test]
+async fn put_gets_405_allowing_get_and_head() {
+    let server = TestServer::single_file().await;
+
+    let response = server
+        .client
+        .put(server.url("/download"))
+        .body("replacement")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let allow = response.headers()[header::ALLOW].to_str().unwrap();
+    let methods: Vec<&str> = allow.split(',').map(str::trim).collect();
+    assert_eq!(methods, ["GET", "HEAD"]);
+    assert_eq!(
+        server.get("/download").await.bytes().await.unwrap(),
+        FIXTURE
+    );
+}
+
+#[tokio::test]
+async fn folder_files_are_served_by_path() {
+    let server = TestServer::folder().await;
+
+    let response = server.get("/files/docs/c.txt").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+
+    let missing = server.get("/files/docs/d.txt").await;
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn clients_outside_allow_are_forbidden() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.allow = vec!["10.0.0.0/8".parse().unwrap()];
+    })
+    .await;
+
+    assert_eq!(
+        server.get("/download").await.status(),
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(server.get("/health").await.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn clients_inside_allow_are_served() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.allow = vec![
+            "10.0.0.0/8".parse().unwrap(),
+            "127.0.0.0/8".parse().unwrap(),
+        ];
+    })
+    .await;
+
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+}
+
+#[tokio::test]
+async fn the_client_address_comes_from_the_connection() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.allow = vec!["10.0.0.0/8".parse().unwrap()];
+    })
+    .await;
+
+    // Only the socket's peer address counts; headers a client sends cannot stand in for it
+    let response = server
+        .client
+        .get(server.url("/download"))
+        .header("X-Forwarded-For", "10.0.0.7")
+        .header("X-Real-IP", "10.0.0.7")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+/// A tus request to `path` carrying the protocol version header.
+fn tus(server: &TestServer, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+    server
+        .client
+        .request(method, server.url(path))
+        .header("tus-resumable", "1.0.0")
+}
+
+#[tokio::test]
+async fn resumable_uploads_resume_from_the_reported_offset() {
+    let server = TestServer::folder_with(|config, dir| {
+        accept_uploads(UploadConflict::Rename)(config, dir);
+        config.resumable = true;
+    })
+    .await;
+
+    // "notes.txt" in base64, as tus clients send it
+    let created = tus(&server, reqwest::Method::POST, "/uploads")
+        .header("upload-length", FIXTURE.len())
+        .header("upload-metadata", "filename bm90ZXMudHh0")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(created.status(), StatusCode::CREATED);
+    assert_eq!(created.headers()["tus-resumable"], "1.0.0");
+    let location = created.headers()[header::LOCATION].to_str().unwrap();
+    assert!(location.starts_with("/uploads/"), "{}", location);
+
+    let patch = |offset: usize, chunk: &'static [u8]| {
+        tus(&server, reqwest::Method::PATCH, location)
+            .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+            .header("upload-offset", offset)
+            .body(chunk)
+            .send()
+    };
+
+    let first = patch(0, &FIXTURE[..10]).await.unwrap();
+    assert_eq!(first.status(), StatusCode::NO_CONTENT);
+    assert_eq!(first.headers()["upload-offset"], "10");
+
+    let offset = tus(&server, reqwest::Method::HEAD, location)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(offset.status(), StatusCode::OK);
+    assert_eq!(offset.headers()["upload-offset"], "10");
+    assert_eq!(offset.headers()["upload-length"], FIXTURE.len().to_string());
+
+    let stale = patch(0, &FIXTURE[..10]).await.unwrap();
+    assert_eq!(stale.status(), StatusCode::CONFLICT);
+
+    let rest = patch(10, &FIXTURE[10..]).await.unwrap();
+    assert_eq!(rest.status(), StatusCode::NO_CONTENT);
+    let saved = std::fs::read(server.dir.path().join("inbox").join("notes.txt")).unwrap();
+    assert_eq!(saved, FIXTURE);
+
+    let finished = tus(&server, reqwest::Method::HEAD, location)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(finished.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn resumable_uploads_need_the_tus_version_header() {
+    let server = TestServer::folder_with(|config, dir| {
+        accept_uploads(UploadConflict::Rename)(config, dir);
+        config.resumable = true;
+    })
+    .await;
+
+    let response = server
+        .client
+        .post(server.url("/uploads"))
+        .header("upload-length", "5")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(response.headers()["tus-version"], "1.0.0");
+}
+
+#[tokio::test]
+async fn refused_resumable_uploads_are_discarded() {
+    let server = TestServer::folder_with(|config, dir| {
+        accept_uploads(UploadConflict::Refuse)(config, dir);
+        config.resumable = true;
+    })
+    .await;
+
+    let created = tus(&server, reqwest::Method::POST, "/uploads")
+        .header("upload-length", FIXTURE.len())
+        .header("upload-metadata", "filename bm90ZXMudHh0")
+        .send()
+        .await
+        .unwrap();
+    let location = created.headers()[header::LOCATION].to_str().unwrap();
+    // The name is taken while the upload is under way
+    let inbox = server.dir.path().join("inbox");
+    std::fs::write(inbox.join("notes.txt"), "taken").unwrap();
+
+    let finished = tus(&server, reqwest::Method::PATCH, location)
+        .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+        .header("upload-offset", 0)
+        .body(FIXTURE)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(finished.status(), StatusCode::CONFLICT);
+
+    let offset = tus(&server, reqwest::Method::HEAD, location)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(offset.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        std::fs::read_to_string(inbox.join("notes.txt")).unwrap(),
+        "taken"
+    );
+    let partial = std::fs::read_dir(inbox.join(".wfpush-partial")).unwrap();
+    assert_eq!(partial.count(), 0);
+}
+
+#[cfg(unix)]
+fn make_fifo(path: &Path) {
+    let status = std::process::Command::new("mkfifo")
+        .arg(path)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn special_files_in_folders_are_forbidden() {
+    let server = TestServer::folder_with(|_, dir| make_fifo(&dir.join("pipe"))).await;
+
+    assert_eq!(
+        server.get("/files/pipe").await.status(),
+        StatusCode::FORBIDDEN
+    );
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn allow_special_streams_a_pipe_without_a_length() {
+    let server = TestServer::start(|config, dir| {
+        let pipe = dir.join("pipe");
+        make_fifo(&pipe);
+        config.paths = vec![pipe];
+        config.allow_special = true;
+    })
+    .await;
+
+    let pipe = server.dir.path().join("pipe");
+    let writer = std::thread::spawn(move || std::fs::write(pipe, FIXTURE).unwrap());
+
+    assert_eq!(server.get("/").await.status(), StatusCode::OK);
+
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+    writer.join().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn stalled_chunked_downloads_end_in_an_error() {
+    let server = TestServer::start(|config, dir| {
+        let pipe = dir.join("pipe");
+        make_fifo(&pipe);
+        config.paths = vec![pipe];
+        config.allow_special = true;
+        config.stall_timeout = Some(std::time::Duration::from_millis(300));
+    })
+    .await;
+
+    // Far more than the socket buffers hold; the writer stops once the server lets go
+    let pipe = server.dir.path().join("pipe");
+    std::thread::spawn(move || {
+        use std::io::Write;
+        let mut pipe = std::fs::OpenOptions::new().write(true).open(pipe).unwrap();
+        while pipe.write_all(&[b'x'; 64 * 1024]).is_ok() {}
+    });
+
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+
+    // Stop reading until the server has given up on us
+    tokio::time::sleep(std::time::Duration::from_millis(1500)).await;
+    assert!(response.bytes().await.is_err());
+}
+
+#[tokio::test]
+async fn max_age_delete_removes_the_file_once_the_limit_triggers() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.max_downloads = Some(1);
+        config.delete_when_done = true;
+    })
+    .await;
+    let file = server.dir.path().join("notes.txt");
+
+    assert_eq!(
+        server.get("/download").await.bytes().await.unwrap(),
+        FIXTURE
+    );
+    assert!(eventually(|| !file.exists()).await);
+}
+
+#[tokio::test]
+async fn files_stay_without_max_age_delete() {
+    let server = TestServer::single_file_with(|config, _| config.max_downloads = Some(1)).await;
+    let file = server.dir.path().join("notes.txt");
+
+    assert_eq!(
+        server.get("/download").await.bytes().await.unwrap(),
+        FIXTURE
+    );
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert!(file.exists());
+}
+
+#[tokio::test]
+async fn stats_count_completed_downloads() {
+    let server = TestServer::single_file().await;
+
+    let before = server.get_json("/stats").await;
+    assert_eq!(before["completed_downloads"], 0);
+    assert_eq!(before["bytes_served"], 0);
+
+    let body = server.get("/download").await.bytes().await.unwrap();
+    assert_eq!(body, FIXTURE);
+
+    let after = server.get_json("/stats").await;
+    assert_eq!(after["completed_downloads"], 1);
+    assert_eq!(after["bytes_served"], FIXTURE.len());
+    assert!(after["uptime_secs"].is_u64());
+}
+
+#[tokio::test]
+async fn landing_page_suggests_a_command_for_the_visitor() {
+    let server = TestServer::single_file().await;
+
+    let page = |agent: &'static str| {
+        server
+            .client
+            .get(server.url("/"))
+            .header(header::USER_AGENT, agent)
+            .send()
+    };
+
+    let unix = page("curl/8.5.0").await.unwrap().text().await.unwrap();
+    assert!(unix.contains("curl -OJ"), "{}", unix);
+
+    let windows = page("Mozilla/5.0 (Windows NT 10.0; Win64; x64)")
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(windows.contains("Invoke-WebRequest -Uri"), "{}", windows);
+    assert!(windows.contains("-OutFile"), "{}", windows);
+}
+
+/// Accepts multipart uploads into `inbox` under `policy`.
+fn accept_uploads(policy: UploadConflict) -> impl FnOnce(&mut ServerConfig, &Path) {
+    move |config, dir| {
+        config.upload = Some(dir.join("inbox"));
+        config.upload_conflict = policy;
+    }
+}
+
+/// Uploads `contents` as `name` in a hand-built multipart form.
+async fn upload(server: &TestServer, name: &str, contents: &str) -> reqwest::Response {
+    let body = format!(
+        "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n{}\r\n--X--\r\n",
+        name, contents
+    );
+    server
+        .client
+        .post(server.url("/upload"))
+        .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn upload_page_posts_to_the_upload_route() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Rename)).await;
+
+    let response = server.get("/upload").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = response.text().await.unwrap();
+    assert!(page.contains(r#"action="/upload""#));
+    assert!(page.contains(r#"type="file""#));
+}
+
+#[tokio::test]
+async fn browser_form_uploads_get_a_success_page() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Rename)).await;
+
+    let response = server
+        .client
+        .post(server.url("/upload"))
+        .header(header::ACCEPT, "text/html,*/*;q=0.8")
+        .header(header::CONTENT_TYPE, "multipart/form-data; boundary=X")
+        .body("--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a&b.txt\"\r\n\r\nhi\r\n--X--\r\n")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(
+        response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html")
+    );
+    assert!(
+        response
+            .text()
+            .await
+            .unwrap()
+            .contains("Uploaded: a&amp;b.txt")
+    );
+}
+
+#[tokio::test]
+async fn uploads_with_a_taken_name_are_renamed() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Rename)).await;
+
+    assert_eq!(
+        upload(&server, "a.txt", "one").await.status(),
+        StatusCode::OK
+    );
+    let second = upload(&server, "a.txt", "two").await;
+    assert_eq!(second.status(), StatusCode::OK);
+    assert!(second.text().await.unwrap().contains("a-1.txt"));
+
+    let inbox = server.dir.path().join("inbox");
+    assert_eq!(std::fs::read_to_string(inbox.join("a.txt")).unwrap(), "one");
+    assert_eq!(
+        std::fs::read_to_string(inbox.join("a-1.txt")).unwrap(),
+        "two"
+    );
+}
+
+#[tokio::test]
+async fn no_overwrite_refuses_a_taken_name() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Refuse)).await;
+
+    assert_eq!(
+        upload(&server, "a.txt", "one").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        upload(&server, "a.txt", "two").await.status(),
+        StatusCode::CONFLICT
+    );
+
+    let inbox = server.dir.path().join("inbox");
+    assert_eq!(std::fs::read_to_string(inbox.join("a.txt")).unwrap(), "one");
+}
+
+#[tokio::test]
+async fn replace_on_upload_overwrites() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Replace)).await;
+
+    assert_eq!(
+        upload(&server, "a.txt", "one").await.status(),
+        StatusCode::OK
+    );
+    assert_eq!(
+        upload(&server, "a.txt", "two").await.status(),
+        StatusCode::OK
+    );
+
+    let inbox = server.dir.path().join("inbox");
+    assert_eq!(std::fs::read_to_string(inbox.join("a.txt")).unwrap(), "two");
+    assert!(!inbox.join("a-1.txt").exists());
+}
+
+#[tokio::test]
+async fn list_returns_json_entries_sorted_by_name() {
+    let server = TestServer::folder().await;
+
+    let list = server.get_json("/list").await;
+    let names: Vec<&str> = list
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["a-large.bin", "b-small.txt", "docs"]);
+
+    let small = &list[1];
+    assert_eq!(small["size"], 1);
+    assert_eq!(small["mime"], "text/plain");
+    assert_eq!(small["url"], "/files/b-small.txt");
+    assert!(small["modified"].is_string());
+    assert_eq!(list[2]["is_dir"], true);
+
+    let nested = server.get_json("/list/docs").await;
+    assert_eq!(nested[0]["name"], "c.txt");
+    assert_eq!(nested[0]["url"], "/files/docs/c.txt");
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn listing_escapes_names_and_encodes_links() {
+    let server = TestServer::folder_with(|_, dir| {
+        std::fs::write(dir.join("<b>\"x\" & y.txt"), FIXTURE).unwrap();
+    })
+    .await;
+
+    let page = server.get("/").await.text().await.unwrap();
+    assert!(!page.contains("<b>"));
+    assert!(page.contains(">&lt;b&gt;&quot;x&quot; &amp; y.txt</a>"));
+    assert!(page.contains(r#"href="/files/%3Cb%3E%22x%22%20%26%20y.txt""#));
+
+    let list = server.get_json("/list").await;
+    let url = list[0]["url"].as_str().unwrap();
+    assert_eq!(url, "/files/%3Cb%3E%22x%22%20%26%20y.txt");
+    assert_eq!(server.get(url).await.bytes().await.unwrap(), FIXTURE);
+}
+
+#[tokio::test]
+async fn basename_only_rejects_anything_below_the_top_level() {
+    let server = TestServer::folder_with(|config, _| config.basename_only = true).await;
+
+    assert_eq!(
+        server.get("/files/b-small.txt").await.status(),
+        StatusCode::OK
+    );
+    for path in [
+        "/files/docs/c.txt",
+        "/files/docs",
+        "/files/docs%2Fc.txt",
+        "/files/..%2F..%2Fetc%2Fpasswd",
+        "/files/docs%5Cc.txt",
+    ] {
+        assert_eq!(
+            server.get(path).await.status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            path
+        );
+    }
+    let listing = server.get("/").await.text().await.unwrap();
+    assert!(!listing.contains("docs"));
+}
+
+#[tokio::test]
+async fn list_sorts_by_size_on_request() {
+    let server = TestServer::folder().await;
+
+    let list = server.get_json("/list?sort=size").await;
+    let names: Vec<&str> = list
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["name"].as_str().unwrap())
+        .collect();
+    assert_eq!(names, ["docs", "b-small.txt", "a-large.bin"]);
+
+    assert_eq!(
+        server.get("/list?sort=colour").await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        server.get("/list/a-large.bin").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn password_file_accepts_plain_and_bcrypt_passwords() {
+    let server = TestServer::single_file_with(|config, dir| {
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        let passwords = dir.join("passwords");
+        std::fs::write(&passwords, format!("# team\nalice:{}\n\nbob:plain\n", hash)).unwrap();
+        config.password_file = Some(passwords);
+    })
+    .await;
+
+    let download = |user: &str, pass: &str| {
+        server
+            .client
+            .get(server.url("/download"))
+            .basic_auth(user, Some(pass))
+            .send()
+    };
+
+    assert_eq!(
+        server.get("/download").await.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        download("alice", "wrong").await.unwrap().status(),
+        StatusCode::UNAUTHORIZED
+    );
+    // The second request is served from the verified cache
+    for _ in 0..2 {
+        let response = download("alice", "s3cret").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+    }
+    assert_eq!(
+        download("bob", "plain").await.unwrap().status(),
+        StatusCode::OK
+    );
+    assert_eq!(server.get("/health").await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn follow_streams_appended_bytes() {
+    let server = TestServer::sharing("build.log", b"line 1\n", |config, _| {
+        config.follow = true;
+        config.follow_timeout = Some(std::time::Duration::from_secs(1));
+    })
+    .await;
+
+    let mut response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key(header::CONTENT_LENGTH));
+    assert_eq!(response.chunk().await.unwrap().unwrap(), "line 1\n");
+
+    let log = server.dir.path().join("build.log");
+    let mut file = std::fs::OpenOptions::new().append(true).open(log).unwrap();
+    std::io::Write::write_all(&mut file, b"line 2\n").unwrap();
+    assert_eq!(response.chunk().await.unwrap().unwrap(), "line 2\n");
+
+    // Nothing more is written, so the idle timeout ends the download
+    assert!(response.chunk().await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn content_type_overrides_the_guess() {
+    let server = TestServer::sharing("module.bin", FIXTURE, |config, _| {
+        config.content_type = Some("application/wasm".to_string());
+    })
+    .await;
+
+    let response = server.get("/download").await;
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/wasm");
+}
+
+#[tokio::test]
+async fn invalid_content_type_fails_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = ServerConfig {
+        paths: vec![dir.path().to_path_buf()],
+        content_type: Some("not a type\n".to_string()),
+        quiet: true,
+        ..ServerConfig::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let error = run_on(config, listener).await.unwrap_err();
+    assert!(error.to_string().contains("Content-Type"), "{}", error);
+}
+
+/// Shares `size` bytes at 8 KiB/s once the first `burst` bytes are sent.
+async fn throttled_server(size: usize, burst: u64) -> TestServer {
+    TestServer::sharing("big.bin", &vec![b'x'; size], |config, _| {
+        config.limit_rate = Some(8 * 1024);
+        config.throttle_after = burst;
+    })
+    .await
+}
+
+#[tokio::test]
+async fn throttle_after_sends_the_burst_at_full_speed() {
+    let server = throttled_server(32 * 1024, 64 * 1024).await;
+
+    let started = std::time::Instant::now();
+    let body = server.get("/download").await.bytes().await.unwrap();
+    assert_eq!(body.len(), 32 * 1024);
+    assert!(started.elapsed() < std::time::Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn limit_rate_applies_once_the_burst_is_spent() {
+    let server = throttled_server(40 * 1024, 32 * 1024).await;
+
+    // 8 KiB past the burst at 8 KiB/s
+    let started = std::time::Instant::now();
+    let body = server.get("/download").await.bytes().await.unwrap();
+    assert_eq!(body.len(), 40 * 1024);
+    assert!(started.elapsed() >= std::time::Duration::from_millis(900));
+}
+
+/// Shares two files through a manifest, one of them behind its own login.
+async fn manifest_server() -> TestServer {
+    TestServer::start(|config, dir| {
+        std::fs::write(dir.join("menu.txt"), FIXTURE).unwrap();
+        std::fs::write(dir.join("payroll.csv"), "name,salary\n").unwrap();
+        let manifest = dir.join("share.toml");
+        std::fs::write(
+            &manifest,
+            r#"
+[[file]]
+path = "menu.txt"
+route = "/menu"
+
+[[file]]
+path = "payroll.csv"
+route = "/payroll"
+user = "finance"
+password = "ledger"
+"#,
+        )
+        .unwrap();
+        config.manifest = Some(manifest);
+    })
+    .await
+}
+
+#[tokio::test]
+async fn manifest_serves_each_file_at_its_route() {
+    let server = manifest_server().await;
+
+    let response = server.get("/menu").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+
+    let listing = server.get_json("/list").await;
+    let urls: Vec<&str> = listing
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["url"].as_str().unwrap())
+        .collect();
+    assert_eq!(urls, ["/menu", "/payroll"]);
+
+    // Manifest files are only reachable at their routes
+    let response = server.get("/files/menu.txt").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn manifest_credentials_guard_only_their_file() {
+    let server = manifest_server().await;
+
+    let response = server.get("/payroll").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .client
+        .get(server.url("/payroll"))
+        .basic_auth("finance", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = server
+        .client
+        .get(server.url("/payroll"))
+        .basic_auth("finance", Some("ledger"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.text().await.unwrap(), "name,salary\n");
+}
+
+#[tokio::test]
+async fn manifest_entries_need_both_user_and_password() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("menu.txt"), FIXTURE).unwrap();
+    let manifest = dir.path().join("share.toml");
+    std::fs::write(
+        &manifest,
+        "[[file]]\npath = \"menu.txt\"\nroute = \"/menu\"\nuser = \"chef\"\n",
+    )
+    .unwrap();
+    let config = ServerConfig {
+        manifest: Some(manifest),
+        quiet: true,
+        ..ServerConfig::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let error = run_on(config, listener).await.unwrap_err();
+    assert!(
+        error.to_string().contains("both user and password"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn webhook_hears_about_completed_downloads() {
+    let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = axum::Router::new().route(
+        "/hook",
+        axum::routing::post(move |body: axum::body::Bytes| async move {
+            events.send(body).unwrap();
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hook = format!("http://{}/hook", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, receiver).await.unwrap() });
+
+    let server = TestServer::single_file_with(|config, _| config.webhook = Some(hook)).await;
+    server.get("/download").await.bytes().await.unwrap();
+
+    let body = tokio::time::timeout(std::time::Duration::from_secs(5), received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let event: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(event["file"], "notes.txt");
+    assert_eq!(event["client"], "127.0.0.1");
+    assert_eq!(event["bytes"], FIXTURE.len());
+    assert!(event["timestamp"].as_u64().unwrap() > 0);
+}
+
+#[tokio::test]
+async fn proxied_files_are_named_after_the_decoded_url() {
+    let upstream = axum::Router::new().route("/{*path}", axum::routing::get(|| async { FIXTURE }));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+
+    for (path, name) in [
+        ("caf%C3%A9%20menu.pdf", "caf%C3%A9%20menu.pdf"),
+        ("notes%2F..%2Fplan%3F.txt", "plan_.txt"),
+        ("%20.%20", "download"),
+    ] {
+        let url = format!("http://{}/files/{}", addr, path);
+        let server = TestServer::start(|config, _| config.proxy = Some(url)).await;
+
+        let response = server.get("/download").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let disposition = response.headers()[header::CONTENT_DISPOSITION]
+            .to_str()
+            .unwrap();
+        assert!(
+            disposition.ends_with(&format!("filename*=UTF-8''{}", name)),
+            "{}",
+            disposition
+        );
+        assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+    }
+}
+
+#[tokio::test]
+async fn tls_negotiates_http2_with_http1_fallback() {
+    let server = TestServer::single_file_with(|config, _| config.tls = true).await;
+    let url = server.url("/download");
+
+    let response = server.get("/download").await;
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+
+    let client = reqwest::Client::builder()
+        .tls_danger_accept_invalid_certs(true)
+        .http1_only()
+        .build()
+        .unwrap();
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+}
+
+#[tokio::test]
+async fn client_ca_requires_a_signed_client_certificate() {
+    let ca_key = rcgen::KeyPair::generate().unwrap();
+    let mut ca_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+    let issuer = rcgen::Issuer::new(ca_params, ca_key);
+
+    let client_key = rcgen::KeyPair::generate().unwrap();
+    let mut client_params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+    client_params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "laptop");
+    client_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+    let client_cert = client_params.signed_by(&client_key, &issuer).unwrap();
+
+    let mut server = TestServer::single_file_with(|config, dir| {
+        let ca = dir.join("ca.pem");
+        std::fs::write(&ca, ca_cert.pem()).unwrap();
+        config.tls = true;
+        config.client_ca = Some(ca);
+    })
+    .await;
+    let anonymous = server.client.get(server.url("/download")).send().await;
+    assert!(anonymous.is_err());
+
+    let identity = format!("{}{}", client_cert.pem(), client_key.serialize_pem());
+    server.client = reqwest::Client::builder()
+        .tls_danger_accept_invalid_certs(true)
+        .identity(reqwest::Identity::from_pem(identity.as_bytes()).unwrap())
+        .build()
+        .unwrap();
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+}
+
+async fn gzip_get(server: &TestServer, range: Option<&str>) -> reqwest::Response {
+    let mut request = server
+        .client
+        .get(server.url("/download"))
+        .header(header::ACCEPT_ENCODING, "gzip");
+    if let Some(range) = range {
+        request = request.header(header::RANGE, range);
+    }
+    request.send().await.unwrap()
+}
+
+#[tokio::test]
+async fn text_files_are_gzipped_but_archives_are_not() {
+    let server = TestServer::folder_with(|_, dir| {
+        std::fs::write(dir.join("bundle.zip"), FIXTURE.repeat(4)).unwrap();
+    })
+    .await;
+    let get = |path: &str| {
+        server
+            .client
+            .get(server.url(path))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .send()
+    };
+
+    let text = get("/files/docs/c.txt").await.unwrap();
+    assert_eq!(text.headers()[header::CONTENT_ENCODING], "gzip");
+    assert!(text.headers().get(header::CONTENT_LENGTH).is_none());
+    assert_eq!(&text.bytes().await.unwrap()[..2], [0x1f, 0x8b]);
+
+    let archive = get("/files/bundle.zip").await.unwrap();
+    assert!(archive.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(archive.bytes().await.unwrap(), FIXTURE.repeat(4));
+}
+
+#[tokio::test]
+async fn ranges_are_never_compressed() {
+    let server = TestServer::single_file().await;
+
+    let response = gzip_get(&server, Some("bytes=4-8")).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(response.bytes().await.unwrap(), &FIXTURE[4..=8]);
+
+    let response = gzip_get(&server, Some("bytes=0-3,10-40")).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    let body = response.text().await.unwrap();
+    assert!(body.contains(std::str::from_utf8(&FIXTURE[10..=40]).unwrap()));
+}
+
+#[tokio::test]
+async fn compressed_downloads_do_not_offer_ranges() {
+    let server = TestServer::single_file().await;
+
+    let response = gzip_get(&server, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    assert!(response.headers().get(header::ACCEPT_RANGES).is_none());
+}
+
+#[tokio::test]
+async fn ranges_skip_the_precompressed_variant() {
+    let server = TestServer::single_file_with(|_, dir| {
+        std::fs::write(dir.join("notes.txt.gz"), b"not really gzip").unwrap();
+    })
+    .await;
+
+    let response = gzip_get(&server, Some("bytes=4-8")).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(response.bytes().await.unwrap(), &FIXTURE[4..=8]);
+
+    let response = gzip_get(&server, None).await;
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(response.bytes().await.unwrap(), "not really gzip");
+}
+
+/// Shares a page with a script in it, with or without security headers.
+async fn html_server(security_headers: bool) -> TestServer {
+    TestServer::sharing("page.html", b"<script>alert(1)</script>", |config, _| {
+        config.security_headers = security_headers;
+    })
+    .await
+}
+
+#[tokio::test]
+async fn pages_get_security_headers() {
+    let server = html_server(true).await;
+
+    let response = server.get("/").await;
+    let headers = response.headers();
+    assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    assert_eq!(headers[header::X_FRAME_OPTIONS], "DENY");
+    let csp = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+    assert!(csp.contains("default-src 'none'"));
+    assert!(csp.contains("frame-ancestors 'none'"));
+}
+
+#[tokio::test]
+async fn served_html_is_sandboxed() {
+    let server = html_server(true).await;
+
+    let response = server.get("/download").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        response.headers()[header::CONTENT_SECURITY_POLICY],
+        "sandbox"
+    );
+    assert_eq!(
+        response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+        "nosniff"
+    );
+}
+
+#[tokio::test]
+async fn security_headers_can_be_turned_off() {
+    let server = html_server(false).await;
+
+    for path in ["/", "/download"] {
+        let response = server.get(path).await;
+        let headers = response.headers();
+        assert!(headers.get(header::X_CONTENT_TYPE_OPTIONS).is_none());
+        assert!(headers.get(header::X_FRAME_OPTIONS).is_none());
+        assert!(headers.get(header::CONTENT_SECURITY_POLICY).is_none());
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Fetches `/manifest` once background hashing has caught up.
+async fn manifest(server: &TestServer) -> serde_json::Value {
+    for _ in 0..100 {
+        let response = server.get("/manifest").await;
+        if response.status() != StatusCode::SERVICE_UNAVAILABLE {
+            return serde_json::from_slice(&response.bytes().await.unwrap()).unwrap();
+        }
+        assert!(response.headers().contains_key(header::RETRY_AFTER));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    panic!("manifest hashing never finished");
+}
+
+#[tokio::test]
+async fn sync_manifest_lists_every_file_with_its_hash() {
+    let server = TestServer::folder().await;
+
+    let first = server.get("/manifest").await;
+    assert_eq!(first.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(first.headers().contains_key(header::RETRY_AFTER));
+
+    let manifest = manifest(&server).await;
+    let entries = manifest.as_array().unwrap();
+    let paths: Vec<&str> = entries
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths, ["a-large.bin", "b-small.txt", "docs/c.txt"]);
+
+    let nested = &entries[2];
+    assert_eq!(nested["size"], FIXTURE.len());
+    assert_eq!(nested["sha256"], sha256_hex(FIXTURE));
+    let body = server
+        .get(nested["url"].as_str().unwrap())
+        .await
+        .bytes()
+        .await
+        .unwrap();
+    assert_eq!(body, FIXTURE);
+    assert_eq!(entries[0]["sha256"], sha256_hex(&[0; 100]));
+
+    // Served from the cache the second time
+    assert_eq!(server.get_json("/manifest").await, manifest);
+}
+
+#[tokio::test]
+async fn sync_manifest_skips_large_files_and_encodes_urls() {
+    let server = TestServer::folder_with(|config, dir| {
+        std::fs::write(dir.join("my notes #1.txt"), FIXTURE).unwrap();
+        config.hash_threshold = 10;
+    })
+    .await;
+
+    let manifest = manifest(&server).await;
+    let entries = manifest.as_array().unwrap();
+    assert_eq!(entries[0]["path"], "a-large.bin");
+    assert!(entries[0]["sha256"].is_null());
+    assert_eq!(entries[1]["sha256"], sha256_hex(b"x"));
+
+    let spaced = entries
+        .iter()
+        .find(|e| e["path"] == "my notes #1.txt")
+        .unwrap();
+    assert_eq!(spaced["url"], "/files/my%20notes%20%231.txt");
+    let body = server.get(spaced["url"].as_str().unwrap()).await;
+    assert_eq!(body.bytes().await.unwrap(), FIXTURE);
+}
+
+#[tokio::test]
+async fn info_never_waits_for_a_hash() {
+    let server = TestServer::folder_with(|config, _| {
+        config.checksum = true;
+        config.hash_threshold = 50;
+    })
+    .await;
+
+    // The first answer comes straight away and hashing starts behind it
+    let first = server.get("/info/docs/c.txt").await;
+    assert_eq!(first.status(), StatusCode::OK);
+    let mut info =
+        serde_json::from_slice::<serde_json::Value>(&first.bytes().await.unwrap()).unwrap();
+    for _ in 0..100 {
+        if info.get("sha256").is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        info = server.get_json("/info/docs/c.txt").await;
+    }
+    assert_eq!(info["sha256"], sha256_hex(FIXTURE));
+
+    // Above --hash-threshold nothing is hashed on its behalf
+    let large = server.get_json("/info/a-large.bin").await;
+    assert_eq!(large["size"], 100);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(
+        server
+            .get_json("/info/a-large.bin")
+            .await
+            .get("sha256")
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn sync_manifest_needs_a_folder() {
+    let server = TestServer::single_file().await;
+    assert_eq!(
+        server.get("/manifest").await.status(),
+        StatusCode::NOT_FOUND
+    );
+}
+
+/// Adds `inside.txt`, a symlink to `docs/c.txt`, and `escape.txt`, one that
+/// leads out of the share to `secret.txt` in `outside`.
+#[cfg(unix)]
+fn add_symlinks(dir: &Path, outside: &Path) {
+    std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+    std::os::unix::fs::symlink(dir.join("docs").join("c.txt"), dir.join("inside.txt")).unwrap();
+    std::os::unix::fs::symlink(outside.join("secret.txt"), dir.join("escape.txt")).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_are_refused_by_default() {
+    let outside = tempfile::tempdir().unwrap();
+    let server = TestServer::folder_with(|_, dir| add_symlinks(dir, outside.path())).await;
+
+    assert_eq!(
+        server.get("/files/docs/c.txt").await.status(),
+        StatusCode::OK
+    );
+    for link in ["/files/inside.txt", "/files/escape.txt"] {
+        assert_eq!(server.get(link).await.status(), StatusCode::FORBIDDEN);
+    }
+    let names = server.get("/").await.text().await.unwrap();
+    assert!(names.contains("b-small.txt"));
+    assert!(!names.contains("inside.txt"));
+    assert!(!names.contains("escape.txt"));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serve_symlinks_still_keeps_them_inside_the_share() {
+    let outside = tempfile::tempdir().unwrap();
+    let server = TestServer::folder_with(|config, dir| {
+        add_symlinks(dir, outside.path());
+        config.serve_symlinks = true;
+    })
+    .await;
+
+    let response = server.get("/files/inside.txt").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), FIXTURE);
+    assert_eq!(
+        server.get("/files/escape.txt").await.status(),
+        StatusCode::FORBIDDEN
+    );
+
+    let list = server.get_json("/list").await;
+    let inside = list
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|e| e["name"] == "inside.txt")
+        .unwrap();
+    assert_eq!(inside["size"], FIXTURE.len());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn folder_selections_leave_symlinks_out() {
+    let outside = tempfile::tempdir().unwrap();
+    let server = TestServer::folder_with(|_, dir| {
+        add_symlinks(dir, outside.path());
+        std::os::unix::fs::symlink(outside.path(), dir.join("docs").join("away")).unwrap();
+    })
+    .await;
+
+    let selection = server
+        .client
+        .post(server.url("/register-selection"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(r#"{"files": [], "dirs": ["docs", "docs/away"]}"#)
+        .send()
+        .await
+        .unwrap();
+    let selection: serde_json::Value =
+        serde_json::from_slice(&selection.bytes().await.unwrap()).unwrap();
+    let id = selection["id"].as_str().unwrap();
+
+    let config = server
+        .get(&format!("/config/{}", id))
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(config.contains("/files/docs/c.txt"));
+    assert!(!config.contains("away"));
+    assert!(!config.contains("secret.txt"));
+}
+
+/// Polls until `check` holds, for at most five seconds.
+async fn eventually(mut check: impl FnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    check()
+}
+
+#[tokio::test]
+async fn aborted_uploads_never_take_the_final_name() {
+    use tokio::io::AsyncWriteExt;
+
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Rename)).await;
+    let inbox = server.dir.path().join("inbox");
+    let target = inbox.join("big.bin");
+
+    let head = "--X\r\nContent-Disposition: form-data; name=\"file\"; filename=\"big.bin\"\r\n\r\n";
+    let mut stream = tokio::net::TcpStream::connect(server.base.trim_start_matches("http://"))
+        .await
+        .unwrap();
+    let request = format!(
+        "POST /upload HTTP/1.1\r\nHost: test\r\nContent-Type: multipart/form-data; boundary=X\r\n\
+         Content-Length: 1000000\r\n\r\n{}",
+        head
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.write_all(&[7; 64 * 1024]).await.unwrap();
+
+    assert!(eventually(|| part_files(&inbox) == 1).await);
+    assert!(!target.exists());
+
+    drop(stream);
+    assert!(eventually(|| part_files(&inbox) == 0).await);
+    assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn finished_uploads_leave_no_part_file() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Rename)).await;
+
+    assert_eq!(
+        upload(&server, "a.txt", "one").await.status(),
+        StatusCode::OK
+    );
+    let inbox = server.dir.path().join("inbox");
+    assert_eq!(std::fs::read_to_string(inbox.join("a.txt")).unwrap(), "one");
+    assert_eq!(part_files(&inbox), 0);
+}
+
+#[tokio::test]
+async fn concurrent_replacing_uploads_never_mix() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Replace)).await;
+
+    let (ones, twos) = ("1".repeat(100_000), "2".repeat(100_000));
+    let (one, two) = tokio::join!(
+        upload(&server, "a.txt", &ones),
+        upload(&server, "a.txt", &twos),
+    );
+    assert_eq!(one.status(), StatusCode::OK);
+    assert_eq!(two.status(), StatusCode::OK);
+    let saved = std::fs::read_to_string(server.dir.path().join("inbox").join("a.txt")).unwrap();
+    assert!(saved == ones || saved == twos);
+}
+
+#[tokio::test]
+async fn uploads_in_progress_are_not_listed() {
+    let server = TestServer::folder_with(|config, dir| {
+        config.upload = Some(dir.to_path_buf());
+        std::fs::create_dir(dir.join(".wfpush-partial")).unwrap();
+        std::fs::write(dir.join(".wfpush-partial").join("x.part"), "half").unwrap();
+    })
+    .await;
+
+    let listing = server.get("/").await.text().await.unwrap();
+    assert!(!listing.contains(".wfpush-partial"));
+    assert_eq!(
+        server.get("/files/.wfpush-partial/x.part").await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let manifest = manifest(&server).await;
+    let paths: Vec<&str> = manifest
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(paths, ["a-large.bin", "b-small.txt", "docs/c.txt"]);
+}
+
+/// How many multipart uploads are being written into `inbox`.
+fn part_files(inbox: &Path) -> usize {
+    std::fs::read_dir(inbox.join(".wfpush-partial")).map_or(0, |entries| {
+        entries
+            .filter(|e| {
+                e.as_ref()
+                    .is_ok_and(|e| e.path().extension().is_some_and(|ext| ext == "part"))
+            })
+            .count()
+    })
+}
+
+/// A range request for `bytes=2-` conditioned on `if_range`.
+async fn if_range(server: &TestServer, if_range: &str) -> reqwest::Response {
+    server
+        .client
+        .get(server.url("/download"))
+        .header(header::RANGE, "bytes=2-")
+        .header(header::IF_RANGE, if_range)
+        .send()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn if_range_matching_the_file_gets_the_range() {
+    let server = TestServer::sharing("data.bin", b"abcdefg", |_, _| {}).await;
+    let first = server.get("/download").await;
+    let etag = first.headers()[header::ETAG].to_str().unwrap().to_string();
+    let modified = first.headers()[header::LAST_MODIFIED]
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    for validator in [&etag, &modified] {
+        let response = if_range(&server, validator).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.bytes().await.unwrap(), &b"cdefg"[..]);
+    }
+    // Weak tags are never good enough for a range
+    let weak = if_range(&server, &format!("W/{}", etag)).await;
+    assert_eq!(weak.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn stale_if_range_gets_the_whole_new_file() {
+    let server = TestServer::sharing("data.bin", b"abcdefg", |_, _| {}).await;
+    let old = server.get("/download").await;
+    let etag = old.headers()[header::ETAG].to_str().unwrap().to_string();
+
+    std::fs::write(server.dir.path().join("data.bin"), b"replaced contents").unwrap();
+
+    let response = if_range(&server, &etag).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(header::CONTENT_RANGE).is_none());
+    assert_eq!(response.bytes().await.unwrap(), &b"replaced contents"[..]);
+
+    let response = if_range(&server, "Thu, 01 Jan 1998 00:00:00 GMT").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.bytes().await.unwrap(), &b"replaced contents"[..]);
+}
+
+#[tokio::test]
+async fn download_names_are_sanitized() {
+    let server = TestServer::single_file_with(|config, _| {
+        config.download_name = Some("../../nul.txt".to_string());
+    })
+    .await;
+
+    let response = server.get("/download").await;
+    let disposition = response.headers()[header::CONTENT_DISPOSITION]
+        .to_str()
+        .unwrap();
+    assert!(
+        disposition.contains(r#"filename="_nul.txt""#),
+        "{}",
+        disposition
+    );
+}
+
+#[tokio::test]
+async fn uploaded_names_are_sanitized() {
+    let server = TestServer::folder_with(accept_uploads(UploadConflict::Rename)).await;
+
+    assert_eq!(
+        upload(&server, r"..\..\CON.txt", "one").await.status(),
+        StatusCode::OK
+    );
+    let inbox = server.dir.path().join("inbox");
+    assert_eq!(
+        std::fs::read_to_string(inbox.join("_CON.txt")).unwrap(),
+        "one"
+    );
+}